@@ -21,7 +21,7 @@ class Homopolymer():
 		self.length = int(bits[4])
 
 class HomoResult():
-	def __init__(self, ra, homo):
+	def __init__(self, ra, homo, flank=30, context_length=5):
 		self.base = homo.base
 		self.homo_length = homo.length
 		self.homo = homo
@@ -30,10 +30,14 @@ class HomoResult():
 		self.stop = ra.get_aligned_index(homo.stop)+1
 		self.read_alignment = ra.whole_read_alignment[self.start: self.stop]
 		self.ref_alignment = ra.whole_ref_seq[self.start: self.stop]
-		self.read_upstream = ra.whole_read_alignment[max(0, self.start-30): self.start]
-		self.read_downstream = ra.whole_read_alignment[self.stop: min(self.stop+30, len(ra.whole_read_alignment))]
-		self.ref_upstream = ra.whole_ref_seq[max(0, self.start-30): self.start]
-		self.ref_downstream = ra.whole_ref_seq[self.stop: min(self.stop+30, len(ra.whole_ref_seq))]
+		self.read_upstream = ra.whole_read_alignment[max(0, self.start-flank): self.start]
+		self.read_downstream = ra.whole_read_alignment[self.stop: min(self.stop+flank, len(ra.whole_read_alignment))]
+		self.ref_upstream = ra.whole_ref_seq[max(0, self.start-flank): self.start]
+		self.ref_downstream = ra.whole_ref_seq[self.stop: min(self.stop+flank, len(ra.whole_ref_seq))]
+		# the context is independent of the flank
+		context_start = max(0, self.start-context_length)
+		self.read_context = ra.whole_read_alignment[context_start: min(self.stop+context_length, len(ra.whole_read_alignment))]
+		self.ref_context = ra.whole_ref_seq[context_start: min(self.stop+context_length, len(ra.whole_ref_seq))]
 		self.length = len(self.read_alignment)
 		self.score = None
 
@@ -290,6 +294,20 @@ def cmdline_args():
 		type=int,
 		help=""
 		)
+	p.add_argument(
+		"--flank",
+		required=False,
+		default=30,
+		type=int,
+		help="bases either side of a homopolymer checked when scoring it"
+		)
+	p.add_argument(
+		"--context_length",
+		required=False,
+		default=5,
+		type=int,
+		help="bases either side of a homopolymer included in the context columns"
+		)
 
 	return p.parse_args()
 
@@ -351,7 +369,7 @@ def read_homo_locfile(file):
 	return homos
 
 
-def process_homo(homo, reads):
+def process_homo(homo, reads, flank, context_length):
 	homo_results = []
 	for read in reads:
 		if homo.start < read.pos:
@@ -359,7 +377,7 @@ def process_homo(homo, reads):
 		if 256 & read.flag or 2048 & read.flag:
 			continue
 		
-		h = HomoResult(read, homo)
+		h = HomoResult(read, homo, flank, context_length)
 
 		score = h.score_homo()
 
@@ -426,6 +444,7 @@ def main(args):
 
 	pool = multiprocessing.Pool(processes=args.threads)
 	homo_results = []
+	homo_process_options = [(homo, reads, args.flank, args.context_length) for homo, reads in homo_process_options]
 	homo_results += pool.starmap(process_homo, homo_process_options, chunksize)
 	pool.close()
 	pool.join()
@@ -435,8 +454,7 @@ def main(args):
 	for h_list in homo_results:
 		for h in h_list:
 			outcontents += (f"{h.homo_length}\t{h.base}\t{h.score}\t"
-				+ f"{h.read_upstream[-5:]+h.read_alignment+h.read_downstream[:5]}"
-				+ f"\t{h.ref_upstream[-5:]+h.ref_alignment+h.ref_downstream[:5]}"
+				+ f"{h.read_context}\t{h.ref_context}"
 				+ f"\t{h.homo.start}\t{h.ra.name}\n")
 	with open(f"{args.outprefix}out.txt", "w") as fout:
 		fout.write(outcontents)
@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::homopolymer::{HomopolymerRecord, HomopolymerScore};
+use crate::summary::SiteSummary;
+
+/// The lengths reads report for homopolymers of each true length and base.
+/// Each cell holds the same counts as a `SiteSummary`, with the observed
+/// length being the true length plus the difference
+#[derive(Debug, Default)]
+pub struct ConfusionMatrix {
+    cells: BTreeMap<(u32, String), SiteSummary>,
+}
+
+impl ConfusionMatrix {
+    pub fn add(&mut self, homo: &HomopolymerRecord, score: &HomopolymerScore) {
+        self.add_score(homo.length, &homo.base, score);
+    }
+
+    /// As `add`, for a homopolymer known only by its length and base
+    pub fn add_score(&mut self, length: u32, base: &str, score: &HomopolymerScore) {
+        self.cells.entry((length, base.to_string())).or_default().add(score);
+    }
+
+    pub fn get(&self, length: u32, base: &str) -> Option<&SiteSummary> {
+        self.cells.get(&(length, base.to_string()))
+    }
+
+    /// cells in order of length then base
+    pub fn cells(&self) -> impl Iterator<Item = (u32, &str, &SiteSummary)> {
+        self.cells.iter().map(|((length, base), cell)| (*length, base.as_str(), cell))
+    }
+
+    /// shortest and longest observed length
+    fn observed_range(&self) -> Option<(i64, i64)> {
+        self.cells().flat_map(|(length, _, cell)| observed(length, cell).map(|(observed, _)| observed))
+            .fold(None, |range, observed| match range {
+                None => Some((observed, observed)),
+                Some((min, max)) => Some((min.min(observed), max.max(observed))),
+            })
+    }
+}
+
+/// observed lengths and their read counts in a cell
+fn observed(length: u32, cell: &SiteSummary) -> impl Iterator<Item = (i64, u64)> + '_ {
+    cell.differences.iter().map(move |(&difference, &n)| (length as i64 + difference as i64, n))
+}
+
+/// reads with an observed length, the denominator of a cell's proportions
+fn scored(cell: &SiteSummary) -> u64 {
+    cell.differences.values().sum()
+}
+
+/// Write one row per length and base, with a column for every observed length
+/// seen in any of `matrices`. A sample column is added for matrices with a
+/// sample name. With `proportions` the observed length columns hold the
+/// fraction of the reads with an observed length rather than read counts
+pub fn write_tsv<W: Write>(matrices: &[(Option<&str>, &ConfusionMatrix)], proportions: bool, writer: &mut W) -> std::io::Result<()> {
+    let range = matrices.iter().filter_map(|(_, matrix)| matrix.observed_range())
+        .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)));
+    let sample_column = matrices.iter().any(|(sample, _)| sample.is_some());
+
+    let mut header = String::from("homo_length\tbase");
+    if sample_column {
+        header.push_str("\tsample");
+    }
+    header.push_str("\tdepth\tskip\tunknown\tmismatch");
+    if let Some((min, max)) = range {
+        for observed in min..=max {
+            header.push_str(&format!("\t{}", observed));
+        }
+    }
+    header.push('\n');
+    writer.write_all(header.as_bytes())?;
+
+    for (sample, matrix) in matrices {
+        for (length, base, cell) in matrix.cells() {
+            let mut row = format!("{}\t{}", length, base);
+            if sample_column {
+                row.push('\t');
+                row.push_str(sample.unwrap_or("NA"));
+            }
+            row.push_str(&format!("\t{}\t{}\t{}\t{}", cell.depth(), cell.skip, cell.unknown, cell.mismatch));
+            if let Some((min, max)) = range {
+                let counts: BTreeMap<i64, u64> = observed(length, cell).collect();
+                let total = scored(cell);
+                for observed in min..=max {
+                    let n = *counts.get(&observed).unwrap_or(&0);
+                    if !proportions {
+                        row.push_str(&format!("\t{}", n));
+                    } else if total == 0 {
+                        row.push_str("\tNA");
+                    } else {
+                        row.push_str(&format!("\t{:.4}", n as f64 / total as f64));
+                    }
+                }
+            }
+            row.push('\n');
+            writer.write_all(row.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `matrices` as a JSON object with a list of cells. Each cell lists
+/// the reads at each observed length with their proportion
+pub fn write_json<W: Write>(matrices: &[(Option<&str>, &ConfusionMatrix)], writer: &mut W) -> std::io::Result<()> {
+    let mut cells = Vec::new();
+    for (sample, matrix) in matrices {
+        for (length, base, cell) in matrix.cells() {
+            let total = scored(cell);
+            let observed: Vec<String> = observed(length, cell)
+                .map(|(observed, n)| format!("{{\"length\": {}, \"count\": {}, \"proportion\": {:.4}}}", observed, n, n as f64 / total as f64))
+                .collect();
+            let mut json = format!("    {{\"homo_length\": {}, \"base\": {}", length, json_string(base));
+            if let Some(sample) = sample {
+                json.push_str(&format!(", \"sample\": {}", json_string(sample)));
+            }
+            json.push_str(&format!(", \"depth\": {}, \"skip\": {}, \"unknown\": {}, \"mismatch\": {}, \"observed\": [{}]}}",
+                cell.depth(), cell.skip, cell.unknown, cell.mismatch, observed.join(", ")));
+            cells.push(json);
+        }
+    }
+    writeln!(writer, "{{\n  \"cells\": [\n{}\n  ]\n}}", cells.join(",\n"))
+}
+
+/// quote and escape `s` as a JSON string
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix() -> ConfusionMatrix {
+        let mut matrix = ConfusionMatrix::default();
+        for (length, base, score) in [(5, "A", "0"), (5, "A", "0"), (5, "A", "-1"), (5, "A", "skip"), (3, "T", "1"), (3, "T", "?"), (7, "G", "skip")] {
+            matrix.add_score(length, base, &score.parse().unwrap());
+        }
+        matrix
+    }
+
+    fn tsv(matrices: &[(Option<&str>, &ConfusionMatrix)], proportions: bool) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        write_tsv(matrices, proportions, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn tsv_counts_and_proportions() {
+        let matrix = matrix();
+        assert_eq!(matrix.get(5, "A").unwrap().depth(), 4);
+        assert!(matrix.get(5, "C").is_none());
+
+        // observed lengths run from 4 to 5, and rows are ordered by length then base
+        assert_eq!(tsv(&[(None, &matrix)], false), "\
+homo_length\tbase\tdepth\tskip\tunknown\tmismatch\t4\t5
+3\tT\t2\t0\t1\t0\t1\t0
+5\tA\t4\t1\t0\t0\t1\t2
+7\tG\t1\t1\t0\t0\t0\t0
+");
+        // proportions are of the reads with a numerical difference
+        assert_eq!(tsv(&[(Some("s1"), &matrix)], true), "\
+homo_length\tbase\tsample\tdepth\tskip\tunknown\tmismatch\t4\t5
+3\tT\ts1\t2\t0\t1\t0\t1.0000\t0.0000
+5\tA\ts1\t4\t1\t0\t0\t0.3333\t0.6667
+7\tG\ts1\t1\t1\t0\t0\tNA\tNA
+");
+        // the observed length columns cover every sample
+        let mut other = ConfusionMatrix::default();
+        other.add_score(5, "A", &"2".parse().unwrap());
+        let both = tsv(&[(Some("s1"), &matrix), (Some("s2"), &other)], false);
+        let lines: Vec<&str> = both.lines().collect();
+        assert_eq!(lines[0], "homo_length\tbase\tsample\tdepth\tskip\tunknown\tmismatch\t4\t5\t6\t7");
+        assert_eq!(lines[1], "3\tT\ts1\t2\t0\t1\t0\t1\t0\t0\t0");
+        assert_eq!(lines[4], "5\tA\ts2\t1\t0\t0\t0\t0\t0\t0\t1");
+
+        // no reads at all
+        assert_eq!(tsv(&[(None, &ConfusionMatrix::default())], false), "homo_length\tbase\tdepth\tskip\tunknown\tmismatch\n");
+    }
+
+    #[test]
+    fn json_cells() {
+        let mut out: Vec<u8> = Vec::new();
+        write_json(&[(Some("s1"), &matrix())], &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), r#"{
+  "cells": [
+    {"homo_length": 3, "base": "T", "sample": "s1", "depth": 2, "skip": 0, "unknown": 1, "mismatch": 0, "observed": [{"length": 4, "count": 1, "proportion": 1.0000}]},
+    {"homo_length": 5, "base": "A", "sample": "s1", "depth": 4, "skip": 1, "unknown": 0, "mismatch": 0, "observed": [{"length": 4, "count": 1, "proportion": 0.3333}, {"length": 5, "count": 2, "proportion": 0.6667}]},
+    {"homo_length": 7, "base": "G", "sample": "s1", "depth": 1, "skip": 1, "unknown": 0, "mismatch": 0, "observed": []}
+  ]
+}
+"#);
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("s1"), "\"s1\"");
+        assert_eq!(json_string("a\"b\\c\td\n\u{1}"), "\"a\\\"b\\\\c\\td\\n\\u0001\"");
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::homopolymer::{HomopolymerIndex, HomopolymerRegion};
+use crate::io::Assembly;
+use crate::read_alignment::AlignmentProjection;
+
+/// A stretch of assembly away from any homopolymer, used to measure the
+/// background indel rate
+#[derive(Debug, Clone)]
+pub struct ControlRegion {
+    pub contig: String,
+    /// 0-based start
+    pub start: u32,
+    /// 0-based, exclusive end
+    pub stop: u32,
+}
+
+/// Control regions of `length` bases in the gaps after each homopolymer, each
+/// at least `pad` bases from the nearest homopolymer. As many as fit are
+/// placed in each gap, one every `length + 2 * pad` bases. The gap after the
+/// last homopolymer on a contig runs to the end of the contig
+pub fn find_control_regions(homos: &HomopolymerIndex, assembly: &Assembly, length: u32, pad: u32) -> Vec<ControlRegion> {
+    let space_needed = length + 2 * pad;
+    let mut regions = Vec::new();
+    if space_needed == 0 {
+        return regions
+    }
+    for contig in homos.contigs() {
+        let contig_len = match assembly.contig_len(contig) {
+            Some(contig_len) => contig_len as u32,
+            None => continue,
+        };
+        let contig_homos = homos.homopolymers(contig);
+        for (i, homo) in contig_homos.iter().enumerate() {
+            let region_end = contig_homos.get(i + 1).map_or(contig_len, |next| next.start);
+            if region_end < homo.stop + space_needed {
+                continue
+            }
+            let num_regions = (region_end - homo.stop) / space_needed;
+            for x in 0..num_regions {
+                let start = homo.stop + x * space_needed + pad;
+                regions.push(ControlRegion { contig: contig.clone(), start, stop: start + length });
+            }
+        }
+    }
+    regions
+}
+
+/// Control regions by contig, to look up the ones a read spans
+#[derive(Debug, Default)]
+pub struct ControlIndex {
+    contigs: Vec<String>,
+    by_contig: HashMap<String, Vec<ControlRegion>>,
+}
+
+impl ControlIndex {
+    pub fn new(regions: Vec<ControlRegion>) -> ControlIndex {
+        let mut index = ControlIndex::default();
+        for region in regions {
+            if !index.by_contig.contains_key(&region.contig) {
+                index.contigs.push(region.contig.clone());
+            }
+            index.by_contig.entry(region.contig.clone()).or_default().push(region);
+        }
+        for regions in index.by_contig.values_mut() {
+            regions.sort_by_key(|region| region.start);
+        }
+        index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_contig.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_contig.values().map(|regions| regions.len()).sum()
+    }
+
+    /// Control regions lying wholly within `start..end` on `contig`, sorted by
+    /// start. Regions do not overlap, so their ends are sorted too
+    pub fn spanned_by(&self, contig: &str, start: u32, end: u32) -> &[ControlRegion] {
+        let regions = match self.by_contig.get(contig) {
+            Some(regions) => regions,
+            None => return &[],
+        };
+        let first = regions.partition_point(|region| region.start < start);
+        let last = regions.partition_point(|region| region.stop <= end);
+        if first < last {
+            &regions[first..last]
+        } else {
+            &[]
+        }
+    }
+
+    /// one region per contig from the first control region to the end of the
+    /// last, e.g. to limit the reads taken from a CRAM file
+    pub fn regions(&self) -> Vec<HomopolymerRegion> {
+        self.contigs.iter().map(|contig| {
+            let regions = &self.by_contig[contig];
+            HomopolymerRegion {
+                contig: contig.clone(),
+                start: regions[0].start,
+                stop: regions[regions.len() - 1].stop,
+            }
+        }).collect()
+    }
+}
+
+/// How a read sequenced a control region
+#[derive(Debug)]
+pub struct ControlResult<'a> {
+    pub region: &'a ControlRegion,
+    /// the read's bases aligned to the region, with gaps as '-'
+    pub read_alignment: String,
+    pub ref_alignment: String,
+    /// number of read bases aligned to the region
+    pub read_length: u32,
+}
+
+impl<'a> ControlResult<'a> {
+    /// `proj` must be the alignment of a read that spans `region`. None if the
+    /// read skips reference (an N cigar operation) in the region
+    pub fn new(region: &'a ControlRegion, proj: &AlignmentProjection) -> Option<ControlResult<'a>> {
+        let (read_alignment, ref_alignment) = proj.extract_alignment(region.start, region.stop);
+        if read_alignment.contains('>') {
+            return None
+        }
+        let read_length = read_alignment.chars().filter(|&c| c != '-').count() as u32;
+        Some(ControlResult { region, read_alignment, ref_alignment, read_length })
+    }
+
+    pub fn ref_length(&self) -> u32 {
+        self.region.stop - self.region.start
+    }
+
+    /// bases inserted (positive) or deleted (negative) in the region
+    pub fn difference(&self) -> i64 {
+        self.read_length as i64 - self.ref_length() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::homopolymer::HomopolymerRecord;
+    use crate::io::FastaSequence;
+    use crate::read_alignment::tests::{alignment, REF};
+
+    fn homopolymer(contig: &str, start: u32) -> HomopolymerRecord {
+        HomopolymerRecord { contig: contig.to_string(), start, stop: start + 5, base: "A".to_string(), length: 5 }
+    }
+
+    /// c1 is 100 bases with homopolymers at 10 and 50, c2 is 30 bases with one
+    /// at 0, and c3 is not in the assembly
+    fn homos_and_assembly() -> (HomopolymerIndex, Assembly) {
+        let homos = HomopolymerIndex::new(vec![homopolymer("c1", 10), homopolymer("c1", 50), homopolymer("c2", 0), homopolymer("c3", 0)]);
+        let mut fasta = FastaSequence { seq_idxs: HashMap::new(), seq_map: HashMap::new() };
+        for (idx, (contig, length)) in [("c1", 100), ("c2", 30)].into_iter().enumerate() {
+            fasta.seq_idxs.insert(idx as i32, contig.to_string());
+            fasta.seq_map.insert(contig.to_string(), "C".repeat(length));
+        }
+        (homos, Assembly::Loaded(fasta))
+    }
+
+    fn spans(regions: &[ControlRegion]) -> Vec<(&str, u32, u32)> {
+        regions.iter().map(|region| (region.contig.as_str(), region.start, region.stop)).collect()
+    }
+
+    #[test]
+    fn control_regions_between_homopolymers() {
+        let (homos, assembly) = homos_and_assembly();
+        // one region every 20 bases, 5 bases from the homopolymer before it, running on to the end of the contig
+        assert_eq!(spans(&find_control_regions(&homos, &assembly, 10, 5)), vec![("c1", 20, 30), ("c1", 60, 70), ("c1", 80, 90), ("c2", 10, 20)]);
+        assert_eq!(spans(&find_control_regions(&homos, &assembly, 10, 10)), vec![("c1", 25, 35), ("c1", 65, 75)]);
+        // gaps too small for a region and its padding
+        assert_eq!(spans(&find_control_regions(&homos, &assembly, 10, 13)), vec![("c1", 68, 78)]);
+        assert!(find_control_regions(&homos, &assembly, 10, 20).is_empty());
+        assert!(find_control_regions(&homos, &assembly, 0, 0).is_empty());
+    }
+
+    #[test]
+    fn spanned_by() {
+        let (homos, assembly) = homos_and_assembly();
+        let controls = ControlIndex::new(find_control_regions(&homos, &assembly, 10, 5));
+        assert_eq!((controls.len(), controls.is_empty()), (4, false));
+        assert!(ControlIndex::new(Vec::new()).is_empty());
+
+        assert_eq!(spans(controls.spanned_by("c1", 0, 100)), vec![("c1", 20, 30), ("c1", 60, 70), ("c1", 80, 90)]);
+        // a read must span the whole region
+        assert_eq!(spans(controls.spanned_by("c1", 20, 70)), vec![("c1", 20, 30), ("c1", 60, 70)]);
+        assert_eq!(spans(controls.spanned_by("c1", 20, 69)), vec![("c1", 20, 30)]);
+        assert_eq!(spans(controls.spanned_by("c1", 21, 100)), vec![("c1", 60, 70), ("c1", 80, 90)]);
+        assert!(controls.spanned_by("c1", 25, 65).is_empty());
+        assert!(controls.spanned_by("c1", 30, 60).is_empty());
+        assert!(controls.spanned_by("c3", 0, 100).is_empty());
+
+        let regions: Vec<(String, u32, u32)> = controls.regions().into_iter().map(|region| (region.contig, region.start, region.stop)).collect();
+        assert_eq!(regions, vec![("c1".to_string(), 20, 90), ("c2".to_string(), 10, 20)]);
+    }
+
+    #[test]
+    fn control_results() {
+        let region = ControlRegion { contig: "c1".to_string(), start: 5, stop: 15 };
+        let result = |pos: u32, cigar: &str, seq: &str| {
+            let ra = alignment(pos, cigar, seq);
+            ControlResult::new(&region, &ra.project(REF)).map(|result| (result.read_alignment.clone(), result.read_length, result.difference()))
+        };
+        assert_eq!(result(0, "30M", REF), Some(("GCAAGGCTTA".to_string(), 10, 0)));
+        assert_eq!(result(0, "8M2D20M", "ACGTTGCAGCTTACCGATGCATCGGATC"), Some(("GCA--GCTTA".to_string(), 8, -2)));
+        assert_eq!(result(0, "10M3I20M", "ACGTTGCAAGGGGGCTTACCGATGCATCGGATC"), Some(("GCAAGGGGGCTTA".to_string(), 13, 3)));
+        // reads that skip reference in the region are left out
+        assert_eq!(result(0, "8M2N20M", "ACGTTGCAGCTTACCGATGCATCGGATC"), None);
+        assert_eq!(ControlResult::new(&region, &alignment(0, "30M", REF).project(REF)).unwrap().ref_length(), 10);
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+/// Errors reported by hope. Each says which file, and where possible which line
+/// and value, caused the problem
+#[derive(Debug)]
+pub enum Error {
+    /// a file could not be opened, read or written
+    Io { path: String, source: std::io::Error },
+    /// a line of an input file could not be understood
+    Parse { path: String, line: usize, message: String },
+    /// the inputs are readable but can't be used together, e.g. the bam and
+    /// assembly disagree about a contig
+    Input(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io<P: AsRef<str>>(path: P, source: std::io::Error) -> Error {
+        Error::Io { path: path.as_ref().to_string(), source }
+    }
+
+    pub fn parse<P: AsRef<str>>(path: P, line: usize, message: String) -> Error {
+        Error::Parse { path: path.as_ref().to_string(), line, message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::Parse { path, line, message } => write!(f, "{}: line {}: {}", path, line, message),
+            Error::Input(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
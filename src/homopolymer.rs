@@ -2,10 +2,15 @@
 #![allow(unused_variables)]
 #![allow(unused_imports)]
 
-#[derive(Debug)]
+use std::collections::HashMap;
+
+/// A homopolymer in the assembly
+#[derive(Debug, Clone)]
 pub struct HomopolymerRecord {
     pub contig: String,
+    /// 0-based start
     pub start: u32,
+    /// 0-based, exclusive end
     pub stop: u32,
     pub base: String,
     pub length: u32,
@@ -17,12 +22,185 @@ impl HomopolymerRecord {
     }
 }
 
+/// Scan a contig for runs of a single base that are at least `min_length` long
+/// (and no longer than `max_length`, if given). Records use the same
+/// coordinates as those read by `io::read_homo_pol_file`
+pub fn find_homopolymers(contig: &str, seq: &str, min_length: u32, max_length: Option<u32>) -> Vec<HomopolymerRecord> {
+    let mut homos: Vec<HomopolymerRecord> = Vec::new();
+    let bases = seq.as_bytes();
+    let mut run_start: usize = 0;
+    while run_start < bases.len() {
+        // soft-masked (lowercase) bases still count towards the run
+        let base = bases[run_start].to_ascii_uppercase();
+        let mut run_stop = run_start + 1;
+        while run_stop < bases.len() && bases[run_stop].to_ascii_uppercase() == base {
+            run_stop += 1;
+        }
+        let length = (run_stop - run_start) as u32;
+        let too_long = match max_length {
+            Some(max_length) => length > max_length,
+            None => false,
+        };
+        // skip runs of N and other ambiguity codes
+        if b"ACGT".contains(&base) && length >= min_length && !too_long {
+            homos.push(HomopolymerRecord {
+                contig: contig.to_string(),
+                start: run_start as u32,
+                stop: run_stop as u32,
+                base: (base as char).to_string(),
+                length,
+            });
+        }
+        run_start = run_stop;
+    }
+    homos
+}
+
+/// Homopolymers grouped by contig and sorted by start, so that the sites covered
+/// by a read can be found without looking at every homopolymer
+#[derive(Debug, Default)]
+pub struct HomopolymerIndex {
+    /// contigs in the order in which they first appear in the homopolymer list
+    contigs: Vec<String>,
+    by_contig: HashMap<String, Vec<HomopolymerRecord>>,
+}
+
+impl HomopolymerIndex {
+    pub fn new(homos: Vec<HomopolymerRecord>) -> HomopolymerIndex {
+        let mut index = HomopolymerIndex::default();
+        for homo in homos {
+            if !index.by_contig.contains_key(&homo.contig) {
+                index.contigs.push(homo.contig.clone());
+            }
+            index.by_contig.entry(homo.contig.clone()).or_default().push(homo);
+        }
+        for homos in index.by_contig.values_mut() {
+            homos.sort_by_key(|h| (h.start, h.stop));
+        }
+        index
+    }
+
+    /// contigs holding at least one homopolymer
+    pub fn contigs(&self) -> &[String] {
+        &self.contigs
+    }
+
+    /// all homopolymers on a contig, sorted by start
+    pub fn homopolymers(&self, contig: &str) -> &[HomopolymerRecord] {
+        match self.by_contig.get(contig) {
+            Some(homos) => homos,
+            None => &[],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_contig.values().map(|homos| homos.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Homopolymers on `contig` lying entirely within the reference span
+    /// `[start, end)`. Only the sites starting inside the span are visited
+    pub fn covered_by(&self, contig: &str, start: u32, end: u32) -> impl Iterator<Item = &HomopolymerRecord> {
+        let homos = self.homopolymers(contig);
+        let first = homos.partition_point(|h| h.start < start);
+        let last = homos.partition_point(|h| h.start < end);
+        homos[first..last].iter().filter(move |h| h.stop <= end)
+    }
+
+    /// Merge neighbouring homopolymers that are no more than `max_gap` bases
+    /// apart into regions that can each be fetched from the bam index with a
+    /// single query, starting a new region once a region would span more than
+    /// `max_span` bases. Every homopolymer lies in exactly one region
+    pub fn regions(&self, max_gap: u32, max_span: u32) -> Vec<HomopolymerRegion> {
+        let mut regions: Vec<HomopolymerRegion> = Vec::new();
+        for contig in &self.contigs {
+            let mut current: Option<HomopolymerRegion> = None;
+            for homo in self.homopolymers(contig) {
+                match current.as_mut() {
+                    Some(region) if homo.start <= region.stop + max_gap && homo.stop <= region.start + max_span => {
+                        region.stop = std::cmp::max(region.stop, homo.stop);
+                    }
+                    _ => {
+                        if let Some(region) = current.take() {
+                            regions.push(region);
+                        }
+                        current = Some(HomopolymerRegion {
+                            contig: contig.clone(),
+                            start: homo.start,
+                            stop: homo.stop,
+                        });
+                    }
+                }
+            }
+            if let Some(region) = current {
+                regions.push(region);
+            }
+        }
+        regions
+    }
+}
+
+/// A stretch of one contig holding homopolymers that lie close enough together
+/// to be fetched from the BAM index with a single query
+#[derive(Debug, Clone)]
+pub struct HomopolymerRegion {
+    pub contig: String,
+    pub start: u32,
+    pub stop: u32,
+}
+
+/// How a read sequenced a homopolymer
 #[derive(Debug, PartialEq)]
 pub enum HomopolymerScore {
+    /// bases inserted (positive) or deleted (negative) in the homopolymer
     Difference(i32),
+    /// "skip", "?" or "mismatch", as described in the README
     Other(String),
 }
 
+impl std::fmt::Display for HomopolymerScore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HomopolymerScore::Difference(score) => write!(f, "{}", score),
+            HomopolymerScore::Other(score) => write!(f, "{}", score),
+        }
+    }
+}
+
+impl std::str::FromStr for HomopolymerScore {
+    type Err = String;
+
+    /// parse a score as written in the difference column
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "skip" | "?" | "mismatch" => Ok(HomopolymerScore::Other(s.to_string())),
+            _ => s.parse::<i32>()
+                .map(HomopolymerScore::Difference)
+                .map_err(|_| format!("score '{}' is not a number, skip, ? or mismatch", s)),
+        }
+    }
+}
+
+/// Bases either side of a homopolymer that are looked at when scoring it, and
+/// that are reported as its context
+#[derive(Debug, Clone, Copy)]
+pub struct FlankLengths {
+    /// flank checked by `HomopolymerResult::score`
+    pub flank: u32,
+    /// flank included in `region_read_aln` and `region_ref_aln`
+    pub context: u32,
+}
+
+impl Default for FlankLengths {
+    fn default() -> FlankLengths {
+        FlankLengths { flank: 30, context: 30 }
+    }
+}
+
+/// The alignment of one read across one homopolymer, and its score
 #[derive(Debug)]
 pub struct HomopolymerResult<'a> {
     pub base: String, 
@@ -44,17 +222,23 @@ pub struct HomopolymerResult<'a> {
 }
 
 impl HomopolymerResult<'_> {
-    pub fn new<'a>(homo: &'a HomopolymerRecord, ra: &'a crate::read_alignment::ReadAlignment, ref_seq: &'a String) -> HomopolymerResult<'a> {
-        let start = ra.get_aligned_index(homo.start);// as usize;
-        let stop = ra.get_aligned_index(homo.stop);// as usize;
-        let up_idx = std::cmp::max(std::cmp::max(homo.start, 30) - 30, ra.pos as u32) as u32;
-        let down_idx = (std::cmp::min(homo.stop, ra.aligned_end as u32 - 30) + 30) as u32;
-        let upstart = ra.get_aligned_index(up_idx);
-        let downstop = ra.get_aligned_index(down_idx);
-        let (reg_read_aln, reg_ref_aln) = ra.extract_alignment(upstart, downstop, ref_seq);
-        let (homo_read_aln, homo_ref_aln) = ra.extract_alignment(start, stop, ref_seq);
-        let (read_up, ref_up) = ra.extract_alignment(upstart, start, ref_seq);
-        let (read_down, ref_down) = ra.extract_alignment(stop, downstop, ref_seq);
+    /// Score one homopolymer in a read. `proj` is the read's alignment, built
+    /// once with `ReadAlignment::project` and shared by every homopolymer in it.
+    /// Flanks are cut short at the ends of the alignment
+    pub fn new<'a>(homo: &'a HomopolymerRecord, ra: &'a crate::read_alignment::ReadAlignment, proj: &crate::read_alignment::AlignmentProjection, flanks: FlankLengths) -> HomopolymerResult<'a> {
+        let start = proj.get_aligned_index(homo.start);// as usize;
+        let stop = proj.get_aligned_index(homo.stop);// as usize;
+        let flank_idx = |length: u32| {
+            let up_idx = std::cmp::max(homo.start.saturating_sub(length), ra.pos as u32);
+            let down_idx = std::cmp::min(homo.stop + length, ra.aligned_end as u32);
+            (proj.get_aligned_index(up_idx), proj.get_aligned_index(down_idx))
+        };
+        let (upstart, downstop) = flank_idx(flanks.flank);
+        let (context_start, context_stop) = flank_idx(flanks.context);
+        let (reg_read_aln, reg_ref_aln) = proj.extract_alignment(context_start, context_stop);
+        let (homo_read_aln, homo_ref_aln) = proj.extract_alignment(start, stop);
+        let (read_up, ref_up) = proj.extract_alignment(upstart, start);
+        let (read_down, ref_down) = proj.extract_alignment(stop, downstop);
         
         let mut hr = HomopolymerResult {
             base: homo.base.to_string(),
@@ -87,8 +271,14 @@ impl HomopolymerResult<'_> {
     pub fn score(&mut self) {
         let base = self.base.chars().nth(0).unwrap();
 
-        // first check if we have flanking sequence to check
-        if self.start == 0 || self.stop == self.region_read_aln.len() {
+        // first check if we have flanking sequence to check. The flank, not the
+        // context length, decides this
+        if self.read_upstream.is_empty() || self.read_downstream.is_empty() {
+            self.score = HomopolymerScore::Other("skip".to_string());
+            return
+        }
+        // reference skipped by the read (N in the cigar, e.g. an intron) in or next to the homopolymer
+        if self.read_alignment.contains('>') || self.read_upstream.ends_with('>') || self.read_downstream.starts_with('>') {
             self.score = HomopolymerScore::Other("skip".to_string());
             return
         }
@@ -271,3 +461,130 @@ impl HomopolymerResult<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_alignment::tests::alignment;
+
+    const REF: &str = "ACGTCAGGCAAAAATCGTGCATTTTTGCAGTCACG";
+
+    fn homopolymer(start: u32, base: &str) -> HomopolymerRecord {
+        HomopolymerRecord { contig: "c1".to_string(), start, stop: start + 5, base: base.to_string(), length: 5 }
+    }
+
+    /// (start, stop, base, length) of each homopolymer
+    fn sites(homos: &[HomopolymerRecord]) -> Vec<(u32, u32, &str, u32)> {
+        homos.iter().map(|h| (h.start, h.stop, h.base.as_str(), h.length)).collect()
+    }
+
+    #[test]
+    fn find_homopolymers_min_and_max_length() {
+        let seq = "ACCCGTTTTAGGGGGC";
+        assert_eq!(sites(&find_homopolymers("c1", seq, 3, None)), vec![(1, 4, "C", 3), (5, 9, "T", 4), (10, 15, "G", 5)]);
+        assert_eq!(sites(&find_homopolymers("c1", seq, 4, None)), vec![(5, 9, "T", 4), (10, 15, "G", 5)]);
+        assert_eq!(sites(&find_homopolymers("c1", seq, 3, Some(4))), vec![(1, 4, "C", 3), (5, 9, "T", 4)]);
+        assert!(find_homopolymers("c1", seq, 6, None).is_empty());
+        assert!(find_homopolymers("c1", "", 1, None).is_empty());
+        assert!(find_homopolymers("c1", seq, 3, None).iter().all(|h| h.contig == "c1"));
+    }
+
+    #[test]
+    fn find_homopolymers_at_contig_ends() {
+        assert_eq!(sites(&find_homopolymers("c1", "AAAACGTTTT", 4, None)), vec![(0, 4, "A", 4), (6, 10, "T", 4)]);
+        // the whole contig
+        assert_eq!(sites(&find_homopolymers("c1", "GGGGG", 3, None)), vec![(0, 5, "G", 5)]);
+    }
+
+    #[test]
+    fn find_homopolymers_lowercase() {
+        // soft-masked bases count, whatever their case, and the base is reported in upper case
+        assert_eq!(sites(&find_homopolymers("c1", "CaaAAaGttttC", 4, None)), vec![(1, 6, "A", 5), (7, 11, "T", 4)]);
+    }
+
+    #[test]
+    fn find_homopolymers_skips_ambiguous_bases() {
+        assert_eq!(sites(&find_homopolymers("c1", "ANNNNNNCCCCnnnnRRRRT", 4, None)), vec![(7, 11, "C", 4)]);
+    }
+
+    fn index() -> HomopolymerIndex {
+        let mut other = homopolymer(5, "C");
+        other.contig = "c2".to_string();
+        // out of order, to be sorted by the index
+        HomopolymerIndex::new(vec![homopolymer(30, "G"), homopolymer(10, "A"), other, homopolymer(20, "T")])
+    }
+
+    fn covered(index: &HomopolymerIndex, contig: &str, start: u32, end: u32) -> Vec<u32> {
+        index.covered_by(contig, start, end).map(|h| h.start).collect()
+    }
+
+    #[test]
+    fn covered_by() {
+        let index = index();
+        assert_eq!(index.contigs(), ["c1", "c2"]);
+        assert_eq!(index.len(), 4);
+        assert_eq!(covered(&index, "c1", 0, 100), vec![10, 20, 30]);
+        // the site at 10 starts before the read
+        assert_eq!(covered(&index, "c1", 11, 100), vec![20, 30]);
+        assert_eq!(covered(&index, "c1", 10, 100), vec![10, 20, 30]);
+        // a site ending exactly at the end of the read is covered, one base further is not
+        assert_eq!(covered(&index, "c1", 20, 35), vec![20, 30]);
+        assert_eq!(covered(&index, "c1", 20, 34), vec![20]);
+        // empty and uncovered spans
+        assert!(covered(&index, "c1", 20, 20).is_empty());
+        assert!(covered(&index, "c1", 21, 29).is_empty());
+        assert!(covered(&index, "c1", 36, 100).is_empty());
+        assert!(covered(&index, "c3", 0, 100).is_empty());
+        assert_eq!(covered(&index, "c2", 0, 10), vec![5]);
+    }
+
+    #[test]
+    fn regions_split_on_gap_and_span() {
+        let index = index();
+        let regions = |max_gap, max_span| -> Vec<(String, u32, u32)> {
+            index.regions(max_gap, max_span).into_iter().map(|r| (r.contig, r.start, r.stop)).collect()
+        };
+        let region = |contig: &str, start, stop| (contig.to_string(), start, stop);
+        // sites 5 bases apart are merged, contigs never are
+        assert_eq!(regions(5, 1000), vec![region("c1", 10, 35), region("c2", 5, 10)]);
+        assert_eq!(regions(4, 1000), vec![region("c1", 10, 15), region("c1", 20, 25), region("c1", 30, 35), region("c2", 5, 10)]);
+        // a region spans at most 15 bases
+        assert_eq!(regions(5, 15), vec![region("c1", 10, 25), region("c1", 30, 35), region("c2", 5, 10)]);
+        assert_eq!(regions(5, 14), vec![region("c1", 10, 15), region("c1", 20, 25), region("c1", 30, 35), region("c2", 5, 10)]);
+        assert!(HomopolymerIndex::default().regions(5, 15).is_empty());
+    }
+
+    #[test]
+    fn context_length_does_not_change_scores() {
+        let homos = [homopolymer(9, "A"), homopolymer(21, "T")];
+        let reads = [
+            alignment(0, "35M", "ACGTCAGGCAAAAATCGTGCATTTTTGCAGTCACG"),
+            alignment(0, "10M1D24M", "ACGTCAGGCAAAATCGTGCATTTTTGCAGTCACG"),
+            alignment(0, "11M1I24M", "ACGTCAGGCAAAAAATCGTGCATTTTTGCAGTCACG"),
+            alignment(7, "28M", "GCAAAAATCGTGCATTTTTGCAGTCACG"),
+            alignment(0, "26M", "ACGTCAGGCAAAAATCGTGCATTTTT"),
+            alignment(0, "6M1D28M", "ACGTCAGCAAAAATCGTGCATTTTTGCAGTCACG"),
+            alignment(0, "35M", "ACGTCAGGCAAGAATCGTGCATTTTTGCAGTCACG"),
+            alignment(3, "2S8M2I24M3S", "TTTCAGGCAAAAAAATCGTGCATTTTTGCAGTCACGGGG"),
+        ];
+        for ra in &reads {
+            let proj = ra.project(REF);
+            for homo in &homos {
+                for flank in [1, 3, 30] {
+                    let expected = HomopolymerResult::new(homo, ra, &proj, FlankLengths { flank, context: 30 });
+                    for context in [0, 1, 2, 5, 100] {
+                        let hr = HomopolymerResult::new(homo, ra, &proj, FlankLengths { flank, context });
+                        assert_eq!(hr.score, expected.score, "{:?} at {}, flank {}, context {}", ra.cig, homo.start, flank, context);
+                    }
+                }
+            }
+        }
+
+        // the context length still sets the context columns
+        let ra = &reads[0];
+        let proj = ra.project(REF);
+        let hr = HomopolymerResult::new(&homos[0], ra, &proj, FlankLengths { flank: 30, context: 2 });
+        assert_eq!(hr.region_read_aln, "GCAAAAATC");
+        assert_eq!(hr.score, HomopolymerScore::Difference(0));
+    }
+}
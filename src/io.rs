@@ -3,14 +3,24 @@
 #![allow(unused_imports)]
 #![allow(unreachable_code)]
 
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::{self, BufRead};
-use std::path::Path;
+use std::io::{self, BufRead, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use bam;
+use flate2::read::MultiGzDecoder;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{Compression, Crc};
 
-use crate::homopolymer::HomopolymerRecord;
+use crate::confusion::ConfusionMatrix;
+use crate::error::{Error, Result};
+use crate::homopolymer::{HomopolymerRecord, HomopolymerRegion, HomopolymerScore};
 use crate::read_alignment::ReadAlignment;
 
 #[derive(Debug)]
@@ -19,41 +29,334 @@ pub struct FastaSequence {
     pub seq_map: HashMap<String, String>
 }
 
+impl FastaSequence {
+    /// contig names in the order they appear in the FASTA file
+    pub fn contig_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for idx in 0..self.seq_idxs.len() as i32 {
+            names.push(self.seq_idxs.get(&idx).unwrap().to_string());
+        }
+        names
+    }
+
+    /// find homopolymers in every contig, in FASTA order
+    pub fn find_homopolymers(&self, min_length: u32, max_length: Option<u32>) -> Vec<HomopolymerRecord> {
+        let mut homos: Vec<HomopolymerRecord> = Vec::new();
+        for contig in self.contig_names() {
+            let seq = self.seq_map.get(&contig).unwrap();
+            homos.extend(crate::homopolymer::find_homopolymers(&contig, seq, min_length, max_length));
+        }
+        homos
+    }
+}
+
 
-pub fn read_homo_pol_file(filename: String) -> Vec<HomopolymerRecord> {
+/// Read a tab-delimited homopolymer file with the columns contig, start, stop,
+/// base and length, where start and stop are 1-based and inclusive
+pub fn read_homo_pol_file(filename: String) -> Result<Vec<HomopolymerRecord>> {
 
     let mut homos: Vec<HomopolymerRecord> = Vec::new();
-    // File hosts must exist in current path before this produces output
-    if let Ok(lines) = read_lines(filename) {
-        for line in lines {
-            if let Ok(l) = line {
-                let mut bits = l.split("\t");
-                let contig: String = bits.next().unwrap().to_string();
-                let start: u32 = bits.next().unwrap().parse::<u32>().unwrap()-1;
-                let stop: u32 = bits.next().unwrap().parse::<u32>().unwrap();
-                let base: String = bits.next().unwrap().to_string();
-                let length: u32 = bits.next().unwrap().parse::<u32>().unwrap();
-                homos.push(HomopolymerRecord{
-                    contig,
-                    start,
-                    stop,
-                    base,
-                    length
-                });
-                
+    let lines = read_lines(&filename).map_err(|e| Error::io(&filename, e))?;
+    for (line_idx, line) in lines.enumerate() {
+        let l = line.map_err(|e| Error::io(&filename, e))?;
+        let line_num = line_idx + 1;
+        let l = l.trim_end_matches('\r');
+        if l.trim().is_empty() {
+            continue
+        }
+        let bits: Vec<&str> = l.split('\t').collect();
+        if bits.len() < 5 {
+            return Err(Error::parse(&filename, line_num, format!("expected 5 tab-separated columns (contig, start, stop, base, length) but found {}", bits.len())))
+        }
+        let contig: String = bits[0].to_string();
+        if contig.is_empty() {
+            return Err(Error::parse(&filename, line_num, "contig is empty".to_string()))
+        }
+        let start: u32 = match bits[1].parse::<u32>() {
+            Ok(start) if start > 0 => start - 1,
+            _ => return Err(Error::parse(&filename, line_num, format!("start '{}' is not a 1-based coordinate", bits[1]))),
+        };
+        let stop: u32 = match bits[2].parse::<u32>() {
+            Ok(stop) if stop > start => stop,
+            Ok(_) => return Err(Error::parse(&filename, line_num, format!("stop '{}' is before start '{}'", bits[2], bits[1]))),
+            _ => return Err(Error::parse(&filename, line_num, format!("stop '{}' is not a 1-based coordinate", bits[2]))),
+        };
+        let base: String = bits[3].to_ascii_uppercase();
+        if !["A", "C", "G", "T"].contains(&base.as_str()) {
+            return Err(Error::parse(&filename, line_num, format!("base '{}' is not one of A, C, G or T", bits[3])))
+        }
+        let length: u32 = match bits[4].parse::<u32>() {
+            Ok(length) => length,
+            _ => return Err(Error::parse(&filename, line_num, format!("length '{}' is not a number", bits[4]))),
+        };
+        homos.push(HomopolymerRecord{
+            contig,
+            start,
+            stop,
+            base,
+            length
+        });
+    }
+    Ok(homos)
+}
+
+/// Format of a homopolymer site file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HomopolymerFormat {
+    /// contig, start, stop, base and length with 1-based, inclusive coordinates
+    Tsv,
+    /// BED with 0-based, half-open coordinates
+    Bed,
+}
+
+impl HomopolymerFormat {
+    /// BED if the file name ends in ".bed", otherwise the homopolymer TSV
+    pub fn from_path(filename: &str) -> HomopolymerFormat {
+        if filename.to_ascii_lowercase().ends_with(".bed") {
+            HomopolymerFormat::Bed
+        } else {
+            HomopolymerFormat::Tsv
+        }
+    }
+}
+
+impl FromStr for HomopolymerFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(HomopolymerFormat::Tsv),
+            "bed" => Ok(HomopolymerFormat::Bed),
+            _ => Err(format!("unknown homopolymer format '{}', expected tsv or bed", s)),
+        }
+    }
+}
+
+/// Read homopolymer sites in the given format, or the one implied by the file
+/// name if none is given. The assembly is needed to fill in the base of BED
+/// intervals
+pub fn read_homopolymers(filename: String, format: Option<HomopolymerFormat>, assembly: &Assembly) -> Result<Vec<HomopolymerRecord>> {
+    match format.unwrap_or_else(|| HomopolymerFormat::from_path(&filename)) {
+        HomopolymerFormat::Tsv => read_homo_pol_file(filename),
+        HomopolymerFormat::Bed => read_bed_file(filename, assembly),
+    }
+}
+
+/// Read homopolymer sites from a BED file. `track` and `browser` lines are
+/// skipped. The name column may give the base ("A") or the length and base
+/// ("7A"); otherwise the base is taken from the assembly, which must hold a
+/// single base over the interval. The length is the length of the interval
+pub fn read_bed_file(filename: String, assembly: &Assembly) -> Result<Vec<HomopolymerRecord>> {
+
+    let mut homos: Vec<HomopolymerRecord> = Vec::new();
+    let lines = read_lines(&filename).map_err(|e| Error::io(&filename, e))?;
+    for (line_idx, line) in lines.enumerate() {
+        let l = line.map_err(|e| Error::io(&filename, e))?;
+        let line_num = line_idx + 1;
+        let l = l.trim_end_matches('\r');
+        if l.trim().is_empty() || l.starts_with('#') || is_bed_header(l, "track") || is_bed_header(l, "browser") {
+            continue
+        }
+        let bits: Vec<&str> = l.split('\t').collect();
+        if bits.len() < 3 {
+            return Err(Error::parse(&filename, line_num, format!("expected at least 3 tab-separated columns (chrom, start, end) but found {}", bits.len())))
+        }
+        let contig: String = bits[0].to_string();
+        if contig.is_empty() {
+            return Err(Error::parse(&filename, line_num, "chrom is empty".to_string()))
+        }
+        let start: u32 = match bits[1].parse::<u32>() {
+            Ok(start) => start,
+            _ => return Err(Error::parse(&filename, line_num, format!("start '{}' is not a 0-based coordinate", bits[1]))),
+        };
+        let stop: u32 = match bits[2].parse::<u32>() {
+            Ok(stop) if stop > start => stop,
+            Ok(_) => return Err(Error::parse(&filename, line_num, format!("end '{}' is not after start '{}'", bits[2], bits[1]))),
+            _ => return Err(Error::parse(&filename, line_num, format!("end '{}' is not a coordinate", bits[2]))),
+        };
+        let length = stop - start;
+
+        let base = match bits.get(3).and_then(|name| parse_bed_name(name)) {
+            Some((base, name_length)) => {
+                if matches!(name_length, Some(name_length) if name_length != length) {
+                    return Err(Error::parse(&filename, line_num, format!("name '{}' does not match the interval length of {}", bits[3], length)))
+                }
+                base
             }
+            None => {
+                let seq_len = match assembly.contig_len(&contig) {
+                    Some(seq_len) => seq_len,
+                    None => return Err(Error::parse(&filename, line_num, format!("contig {} is not in the assembly", contig))),
+                };
+                if stop as usize > seq_len {
+                    return Err(Error::parse(&filename, line_num, format!("interval runs past the end of contig {} ({} bases)", contig, seq_len)))
+                }
+                let bases = assembly.fetch(&contig, start, stop)?.to_ascii_uppercase();
+                let base = &bases[..1];
+                if !["A", "C", "G", "T"].contains(&base) || bases.chars().any(|b| b.to_string() != base) {
+                    return Err(Error::parse(&filename, line_num, format!("assembly has {} over the interval, which is not a homopolymer", bases)))
+                }
+                base.to_string()
+            }
+        };
+        homos.push(HomopolymerRecord{
+            contig,
+            start,
+            stop,
+            base,
+            length
+        });
+    }
+    Ok(homos)
+}
+
+/// base and, if given, length from a BED name such as "A" or "7A"
+fn parse_bed_name(name: &str) -> Option<(String, Option<u32>)> {
+    let name = name.to_ascii_uppercase();
+    let base = name.get(name.len().checked_sub(1)?..)?;
+    if !["A", "C", "G", "T"].contains(&base) {
+        return None
+    }
+    let digits = &name[..name.len() - 1];
+    if digits.is_empty() {
+        return Some((base.to_string(), None))
+    }
+    let length = digits.parse::<u32>().ok()?;
+    Some((base.to_string(), Some(length)))
+}
+
+pub fn write_homo_pol_file(filename: String, homos: &[HomopolymerRecord]) -> Result<()> {
+    let mut outlines: Vec<String> = Vec::new();
+    for homo in homos {
+        // convert start back to 1-based coordinate
+        outlines.push(format!("{0}\t{1}\t{2}\t{3}\t{4}\n", homo.contig, homo.start + 1, homo.stop, homo.base, homo.length));
+    }
+    std::fs::write(&filename, outlines.join("")).map_err(|e| Error::io(&filename, e))
+}
+
+/// Rebuild the confusion matrix from an output file written by hope, using its
+/// homopolymer_length, homopolymer_base and difference columns
+pub fn read_confusion_matrix(filename: String) -> Result<ConfusionMatrix> {
+    let mut matrix = ConfusionMatrix::default();
+    let mut lines = read_lines(&filename).map_err(|e| Error::io(&filename, e))?;
+    let header = match lines.next() {
+        Some(header) => header.map_err(|e| Error::io(&filename, e))?,
+        None => return Err(Error::parse(&filename, 1, "file is empty".to_string())),
+    };
+    let columns: Vec<&str> = header.trim_end_matches('\r').split('\t').collect();
+    let column = |name: &str| columns.iter().position(|&column| column == name)
+        .ok_or_else(|| Error::parse(&filename, 1, format!("no {} column in the header", name)));
+    let (length_idx, base_idx, score_idx) = (column("homopolymer_length")?, column("homopolymer_base")?, column("difference")?);
+    let needed = length_idx.max(base_idx).max(score_idx) + 1;
+
+    for (line_idx, line) in lines.enumerate() {
+        let l = line.map_err(|e| Error::io(&filename, e))?;
+        // the header is line 1
+        let line_num = line_idx + 2;
+        let l = l.trim_end_matches('\r');
+        if l.trim().is_empty() {
+            continue
         }
+        let bits: Vec<&str> = l.split('\t').collect();
+        if bits.len() < needed {
+            return Err(Error::parse(&filename, line_num, format!("expected at least {} tab-separated columns but found {}", needed, bits.len())))
+        }
+        let length: u32 = match bits[length_idx].parse::<u32>() {
+            Ok(length) => length,
+            _ => return Err(Error::parse(&filename, line_num, format!("length '{}' is not a number", bits[length_idx]))),
+        };
+        let score: HomopolymerScore = bits[score_idx].parse().map_err(|e| Error::parse(&filename, line_num, e))?;
+        matrix.add_score(length, bits[base_idx], &score);
     }
-    homos
+    Ok(matrix)
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
+/// Is `line` a BED header line starting with `keyword`? Contigs such as
+/// `track_1` are not headers
+fn is_bed_header(line: &str, keyword: &str) -> bool {
+    match line.strip_prefix(keyword) {
+        Some(rest) => rest.is_empty() || rest.starts_with(' ') || rest.starts_with('\t'),
+        None => false,
+    }
+}
+
+fn read_lines<P>(filename: P) -> io::Result<io::Lines<Box<dyn BufRead>>>
 where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+    let mut reader = io::BufReader::new(File::open(filename)?);
+    // gzip and bgzip files are decompressed as they are read
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        let decoder: Box<dyn BufRead> = Box::new(io::BufReader::new(MultiGzDecoder::new(reader)));
+        return Ok(decoder.lines())
+    }
+    let reader: Box<dyn BufRead> = Box::new(reader);
+    Ok(reader.lines())
 }
 
-pub fn read_fasta(filename: String) -> FastaSequence {
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A sample and the bam or cram file holding its reads
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: String,
+    pub path: String,
+}
+
+impl Sample {
+    /// Name the sample after its file, without the directory or extension.
+    /// Reads from stdin (`-`) are named "stdin"
+    pub fn from_path(path: &str) -> Sample {
+        let name = if path == "-" {
+            "stdin".to_string()
+        } else {
+            let file_name = Path::new(path).file_name().map_or(path.to_string(), |f| f.to_string_lossy().to_string());
+            let stem = [".bam", ".cram", ".sam"].iter().find_map(|ext| file_name.strip_suffix(ext));
+            stem.unwrap_or(&file_name).to_string()
+        };
+        Sample { name, path: path.to_string() }
+    }
+}
+
+/// Read a tab-delimited sample sheet with the columns sample and path (a bam
+/// or cram file, or - for stdin). Blank lines and lines starting with '#' are
+/// skipped
+pub fn read_sample_sheet(filename: String) -> Result<Vec<Sample>> {
+    let mut samples: Vec<Sample> = Vec::new();
+    let lines = read_lines(&filename).map_err(|e| Error::io(&filename, e))?;
+    for (line_idx, line) in lines.enumerate() {
+        let l = line.map_err(|e| Error::io(&filename, e))?;
+        let l = l.trim_end_matches('\r');
+        if l.trim().is_empty() || l.starts_with('#') {
+            continue
+        }
+        let bits: Vec<&str> = l.split('\t').collect();
+        if bits.len() < 2 || bits[0].is_empty() || bits[1].is_empty() {
+            return Err(Error::parse(&filename, line_idx + 1, "expected a sample name and a bam file separated by a tab".to_string()))
+        }
+        samples.push(Sample { name: bits[0].to_string(), path: bits[1].to_string() });
+    }
+    if samples.is_empty() {
+        return Err(Error::Input(format!("{}: no samples found", filename)))
+    }
+    Ok(samples)
+}
+
+/// Sample names must be unique, and stdin can only be read once
+pub fn check_samples(samples: &[Sample]) -> Result<()> {
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    for sample in samples {
+        if let Some(path) = names.insert(&sample.name, &sample.path) {
+            return Err(Error::Input(format!("sample {} is given for both {} and {}", sample.name, path, sample.path)))
+        }
+    }
+    if samples.iter().filter(|sample| sample.path == "-").count() > 1 {
+        return Err(Error::Input("stdin (-) can only be read for one sample".to_string()))
+    }
+    Ok(())
+}
+
+/// Read every sequence in a FASTA file, which may be gzip or bgzip compressed.
+/// Contigs are named by the first word of their header line, as in bam headers
+pub fn read_fasta(filename: String) -> Result<FastaSequence> {
 
     let mut fasta_map: HashMap<String, String> = HashMap::new();
     let mut seq_idxs: HashMap<i32, String> = HashMap::new();
@@ -61,31 +364,954 @@ pub fn read_fasta(filename: String) -> FastaSequence {
     let mut seq = String::new();
     let mut seq_id: i32 = 0;
 
-    if let Ok(lines) = read_lines(filename) {
-        for line in lines {
-            if let Ok(l) = line {
-                if l.starts_with(">") {
-                    if header.is_empty() {
-                        header.push_str(&l[1..]);
-                        seq.clear();
-                    } else {
-                        fasta_map.insert(header.to_string(), seq.to_string());
-                        seq_idxs.insert(seq_id, header.to_string());
-                        seq_id += 1;
-                        header.clear();
-                        header.push_str(&l[1..]);
-                        seq.clear();
-                    }
-                } else {
-                    seq.push_str(l.as_str());
-                }
+    let lines = read_lines(&filename).map_err(|e| Error::io(&filename, e))?;
+    for (line_idx, line) in lines.enumerate() {
+        let l = line.map_err(|e| Error::io(&filename, e))?;
+        let l = l.trim_end_matches('\r');
+        if let Some(name) = l.strip_prefix('>') {
+            if !header.is_empty() {
+                fasta_map.insert(header.to_string(), seq.to_string());
+                seq_idxs.insert(seq_id, header.to_string());
+                seq_id += 1;
+            }
+            header.clear();
+            header.push_str(name.split_whitespace().next().unwrap_or(""));
+            if header.is_empty() {
+                return Err(Error::parse(&filename, line_idx + 1, "sequence has no name".to_string()))
             }
+            if fasta_map.contains_key(&header) {
+                return Err(Error::parse(&filename, line_idx + 1, format!("sequence {} appears more than once", header)))
+            }
+            seq.clear();
+        } else if header.is_empty() {
+            if !l.trim().is_empty() {
+                return Err(Error::parse(&filename, line_idx + 1, "sequence found before the first '>' header line".to_string()))
+            }
+        } else {
+            seq.push_str(l.trim_end());
         }
-        fasta_map.insert(header.to_string(), seq.to_string());
-        seq_idxs.insert(seq_id, header.to_string());
     }
-    FastaSequence {
+    if header.is_empty() {
+        return Err(Error::Input(format!("{}: no sequences found, is this a FASTA file?", filename)))
+    }
+    fasta_map.insert(header.to_string(), seq.to_string());
+    seq_idxs.insert(seq_id, header.to_string());
+
+    Ok(FastaSequence {
         seq_map: fasta_map,
-        seq_idxs: seq_idxs
+        seq_idxs
+    })
+}
+
+/// The assembly the reads were aligned to. Sequences are read from disk as they
+/// are needed if the FASTA file has a samtools faidx index, otherwise the whole
+/// file is loaded
+pub enum Assembly {
+    Loaded(FastaSequence),
+    Indexed(IndexedFasta),
+}
+
+impl Assembly {
+    /// Use the index if there is a `.fai` (and, for bgzip files, a `.gzi`)
+    /// next to the FASTA file
+    pub fn open(filename: String) -> Result<Assembly> {
+        if IndexedFasta::is_indexed(&filename)? {
+            return Ok(Assembly::Indexed(IndexedFasta::open(filename)?))
+        }
+        Ok(Assembly::Loaded(read_fasta(filename)?))
+    }
+
+    /// contig names in the order they appear in the FASTA file
+    pub fn contig_names(&self) -> Vec<String> {
+        match self {
+            Assembly::Loaded(fasta_seq) => fasta_seq.contig_names(),
+            Assembly::Indexed(fasta) => fasta.contig_names(),
+        }
+    }
+
+    pub fn contig_len(&self, contig: &str) -> Option<usize> {
+        match self {
+            Assembly::Loaded(fasta_seq) => fasta_seq.seq_map.get(contig).map(|seq| seq.len()),
+            Assembly::Indexed(fasta) => fasta.contig_len(contig),
+        }
+    }
+
+    /// The sequence of `contig` from the 0-based `start` to the exclusive `stop`
+    pub fn fetch(&self, contig: &str, start: u32, stop: u32) -> Result<Cow<'_, str>> {
+        match self {
+            Assembly::Loaded(fasta_seq) => {
+                let seq = fasta_seq.seq_map.get(contig)
+                    .ok_or_else(|| Error::Input(format!("contig {} is not in the assembly", contig)))?;
+                let window = seq.get(start as usize..stop as usize)
+                    .ok_or_else(|| Error::Input(format!("{}:{}-{} runs past the end of contig {} ({} bases)", contig, start, stop, contig, seq.len())))?;
+                Ok(Cow::Borrowed(window))
+            }
+            Assembly::Indexed(fasta) => Ok(Cow::Owned(fasta.fetch(contig, start, stop)?)),
+        }
+    }
+
+    /// find homopolymers in every contig, in FASTA order. Indexed contigs are
+    /// read one at a time
+    pub fn find_homopolymers(&self, min_length: u32, max_length: Option<u32>) -> Result<Vec<HomopolymerRecord>> {
+        let fasta = match self {
+            Assembly::Loaded(fasta_seq) => return Ok(fasta_seq.find_homopolymers(min_length, max_length)),
+            Assembly::Indexed(fasta) => fasta,
+        };
+        let mut homos: Vec<HomopolymerRecord> = Vec::new();
+        for contig in fasta.contig_names() {
+            let seq = fasta.fetch(&contig, 0, fasta.contig_len(&contig).unwrap() as u32)?;
+            homos.extend(crate::homopolymer::find_homopolymers(&contig, &seq, min_length, max_length));
+        }
+        Ok(homos)
+    }
+}
+
+/// One line of a `.fai` index
+#[derive(Debug, Clone)]
+struct FaiEntry {
+    name: String,
+    length: u64,
+    /// byte offset of the first base in the (uncompressed) file
+    offset: u64,
+    line_bases: u64,
+    line_width: u64,
+}
+
+impl FaiEntry {
+    /// byte offset of the 0-based position `pos` in the (uncompressed) file
+    fn byte_offset(&self, pos: u64) -> u64 {
+        self.offset + pos / self.line_bases * self.line_width + pos % self.line_bases
+    }
+}
+
+/// A FASTA file indexed with `samtools faidx`, optionally bgzip compressed
+#[derive(Debug)]
+pub struct IndexedFasta {
+    filename: String,
+    /// read with positional reads, so threads share it without a lock
+    file: File,
+    contigs: Vec<FaiEntry>,
+    by_name: HashMap<String, usize>,
+    /// (compressed, uncompressed) offsets of the start of each bgzip block,
+    /// from the `.gzi` index. None if the file is not compressed
+    blocks: Option<Vec<(u64, u64)>>,
+}
+
+impl IndexedFasta {
+    /// Is there a `.fai`, and a `.gzi` if the file is compressed?
+    pub fn is_indexed(filename: &str) -> Result<bool> {
+        if !Path::new(&format!("{}.fai", filename)).exists() {
+            return Ok(false)
+        }
+        Ok(!is_gzipped(filename)? || Path::new(&format!("{}.gzi", filename)).exists())
+    }
+
+    pub fn open(filename: String) -> Result<IndexedFasta> {
+        let fai = format!("{}.fai", filename);
+        let mut contigs: Vec<FaiEntry> = Vec::new();
+        let mut by_name: HashMap<String, usize> = HashMap::new();
+        let lines = read_lines(&fai).map_err(|e| Error::io(&fai, e))?;
+        for (line_idx, line) in lines.enumerate() {
+            let l = line.map_err(|e| Error::io(&fai, e))?;
+            if l.trim().is_empty() {
+                continue
+            }
+            let bits: Vec<&str> = l.split('\t').collect();
+            if bits.len() < 5 {
+                return Err(Error::parse(&fai, line_idx + 1, format!("expected 5 tab-separated columns but found {}", bits.len())))
+            }
+            let mut fields: Vec<u64> = Vec::with_capacity(4);
+            for bit in &bits[1..5] {
+                match bit.parse::<u64>() {
+                    Ok(field) => fields.push(field),
+                    _ => return Err(Error::parse(&fai, line_idx + 1, format!("'{}' is not a number", bit))),
+                }
+            }
+            if fields[2] == 0 || fields[3] < fields[2] {
+                return Err(Error::parse(&fai, line_idx + 1, "line lengths are not valid".to_string()))
+            }
+            if by_name.insert(bits[0].to_string(), contigs.len()).is_some() {
+                return Err(Error::parse(&fai, line_idx + 1, format!("sequence {} appears more than once", bits[0])))
+            }
+            contigs.push(FaiEntry {
+                name: bits[0].to_string(),
+                length: fields[0],
+                offset: fields[1],
+                line_bases: fields[2],
+                line_width: fields[3],
+            });
+        }
+
+        let blocks = if is_gzipped(&filename)? {
+            Some(read_gzi(&format!("{}.gzi", filename))?)
+        } else {
+            None
+        };
+        let file = File::open(&filename).map_err(|e| Error::io(&filename, e))?;
+        Ok(IndexedFasta {
+            filename,
+            file,
+            contigs,
+            by_name,
+            blocks,
+        })
+    }
+
+    /// contig names in the order they appear in the index
+    pub fn contig_names(&self) -> Vec<String> {
+        self.contigs.iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    pub fn contig_len(&self, contig: &str) -> Option<usize> {
+        self.by_name.get(contig).map(|&idx| self.contigs[idx].length as usize)
+    }
+
+    /// Read the sequence of `contig` from the 0-based `start` to the exclusive
+    /// `stop`. Safe to call from several threads
+    pub fn fetch(&self, contig: &str, start: u32, stop: u32) -> Result<String> {
+        let entry = match self.by_name.get(contig) {
+            Some(&idx) => &self.contigs[idx],
+            None => return Err(Error::Input(format!("{}: contig {} is not in the index", self.filename, contig))),
+        };
+        if stop as u64 > entry.length {
+            return Err(Error::Input(format!("{}:{}-{} runs past the end of contig {} ({} bases)", contig, start, stop, contig, entry.length)))
+        }
+        if start >= stop {
+            return Ok(String::new())
+        }
+        let from = entry.byte_offset(start as u64);
+        let to = entry.byte_offset(stop as u64 - 1) + 1;
+        let mut bytes = vec![0u8; (to - from) as usize];
+        self.read_at(from, &mut bytes).map_err(|e| Error::io(&self.filename, e))?;
+        bytes.retain(|&b| b != b'\n' && b != b'\r');
+        String::from_utf8(bytes)
+            .map_err(|_| Error::Input(format!("{}: sequence of {} is not valid UTF-8", self.filename, contig)))
+    }
+
+    /// fill `buf` from the uncompressed byte `offset`
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let blocks = match &self.blocks {
+            Some(blocks) => blocks,
+            None => return self.file.read_exact_at(buf, offset),
+        };
+        // start decompressing at the last block beginning at or before offset
+        let block_idx = blocks.partition_point(|&(_, uncompressed)| uncompressed <= offset) - 1;
+        let (compressed, uncompressed) = blocks[block_idx];
+        let mut decoder = MultiGzDecoder::new(io::BufReader::new(FileAt { file: &self.file, offset: compressed }));
+        io::copy(&mut (&mut decoder).take(offset - uncompressed), &mut io::sink())?;
+        decoder.read_exact(buf)
+    }
+}
+
+/// Reads a shared file from `offset` on without moving its cursor
+struct FileAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl Read for FileAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+fn is_gzipped(filename: &str) -> Result<bool> {
+    let mut magic = [0u8; 2];
+    let mut file = File::open(filename).map_err(|e| Error::io(filename, e))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == GZIP_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(Error::io(filename, e)),
+    }
+}
+
+/// Read a bgzip `.gzi` index: the number of entries, then the compressed and
+/// uncompressed offset of each block after the first, as little-endian u64s
+fn read_gzi(filename: &str) -> Result<Vec<(u64, u64)>> {
+    let bytes = std::fs::read(filename).map_err(|e| Error::io(filename, e))?;
+    let values: Vec<u64> = bytes.chunks_exact(8).map(|b| u64::from_le_bytes(b.try_into().unwrap())).collect();
+    if bytes.len() % 8 != 0 || values.is_empty() || values.len() as u64 != values[0] * 2 + 1 {
+        return Err(Error::Input(format!("{}: not a valid .gzi index", filename)))
+    }
+    let mut blocks: Vec<(u64, u64)> = vec![(0, 0)];
+    blocks.extend(values[1..].chunks_exact(2).map(|pair| (pair[0], pair[1])));
+    Ok(blocks)
+}
+
+/// Compare the assembly with the reference sequences in the bam header. Contigs
+/// holding homopolymers must be present in both, and contigs found in both must
+/// have the same length. The error lists every problem found
+pub fn check_contigs(header: &bam::Header, assembly: &Assembly, homo_contigs: &[&str]) -> Result<()> {
+    let references: Vec<(&str, u32)> = header.reference_names().iter().enumerate()
+        .map(|(ref_id, name)| (name.as_str(), header.reference_len(ref_id as u32).unwrap()))
+        .collect();
+    check_references(&references, assembly, homo_contigs)
+}
+
+/// `check_contigs` for the name and length of each reference sequence in the
+/// bam header
+fn check_references(references: &[(&str, u32)], assembly: &Assembly, homo_contigs: &[&str]) -> Result<()> {
+    let mut problems: Vec<String> = Vec::new();
+    let in_header: HashMap<&str, u32> = references.iter().copied().collect();
+    for contig in homo_contigs {
+        if assembly.contig_len(contig).is_none() {
+            problems.push(format!("contig {} has homopolymers but is not in the assembly", contig));
+        }
+        if !in_header.contains_key(contig) {
+            problems.push(format!("contig {} has homopolymers but is not in the bam header", contig));
+        }
+    }
+    for &(name, bam_len) in references {
+        if let Some(seq_len) = assembly.contig_len(name) {
+            if bam_len as usize != seq_len {
+                problems.push(format!("contig {} is {} bases long in the bam header but {} bases long in the assembly", name, bam_len, seq_len));
+            }
+        }
+    }
+    if problems.is_empty() {
+        return Ok(())
+    }
+    Err(Error::Input(format!("the bam file and assembly do not match:\n    {}", problems.join("\n    "))))
+}
+
+/// Does the file start with the CRAM magic number?
+pub fn is_cram(filename: &str) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(filename).map_err(|e| Error::io(filename, e))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == b"CRAM"),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(Error::io(filename, e)),
+    }
+}
+
+/// Alignments read from a CRAM file by `samtools view`, which decodes them
+/// against the assembly and passes them on as uncompressed BAM. Records come
+/// in reference order and each record is read once, however many regions it
+/// overlaps
+pub struct CramReader {
+    filename: String,
+    child: Child,
+    reader: bam::BamReader<ChildStdout>,
+    /// regions handed to samtools, removed when the reader is dropped
+    regions_file: PathBuf,
+}
+
+impl CramReader {
+    /// Only reads overlapping `regions` are decoded, which needs a CRAM index
+    /// (.crai). `threads` is passed on to samtools
+    pub fn open(filename: &str, reference: &str, regions: &[HomopolymerRegion], threads: usize) -> Result<CramReader> {
+        let mut bed = String::new();
+        for region in regions {
+            bed.push_str(&format!("{}\t{}\t{}\n", region.contig, region.start, region.stop));
+        }
+        let regions_file = create_temp_file("regions.bed", bed.as_bytes())?;
+
+        let child = Command::new("samtools")
+            // uncompressed bam of the reads overlapping the regions, each read once
+            .args(["view", "-u", "-M", "-T", reference])
+            .arg("-@").arg(threads.saturating_sub(1).to_string())
+            .arg("-L").arg(&regions_file)
+            .arg(filename)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                let _ = std::fs::remove_file(&regions_file);
+                return Err(Error::Input(format!("{}: samtools is needed to read CRAM files but could not be run: {}", filename, e)))
+            }
+        };
+        let stdout = child.stdout.take().unwrap();
+        let reader = match bam::BamReader::from_stream(stdout, 0) {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = child.wait();
+                let _ = std::fs::remove_file(&regions_file);
+                return Err(Error::Input(format!("{}: samtools view could not decode the CRAM file: {}", filename, e)))
+            }
+        };
+        Ok(CramReader {
+            filename: filename.to_string(),
+            child,
+            reader,
+            regions_file,
+        })
+    }
+
+    pub fn header(&self) -> &bam::Header {
+        self.reader.header()
+    }
+
+    pub fn records(&mut self) -> &mut bam::BamReader<ChildStdout> {
+        &mut self.reader
+    }
+
+    /// Wait for samtools to exit, failing if it did not decode the whole file
+    pub fn finish(mut self) -> Result<()> {
+        let status = self.child.wait().map_err(|e| Error::io(&self.filename, e))?;
+        if !status.success() {
+            return Err(Error::Input(format!("{}: samtools view failed ({})", self.filename, status)))
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CramReader {
+    fn drop(&mut self) {
+        // samtools is still running if we stopped reading early or `finish`
+        // was not called
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        let _ = std::fs::remove_file(&self.regions_file);
+    }
+}
+
+/// Create a new file in the temp dir holding `contents`, with a name no other
+/// file has. An existing file is never opened, so it can't be swapped for a
+/// link to somewhere else
+fn create_temp_file(suffix: &str, contents: &[u8]) -> Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.subsec_nanos());
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("hope-{}-{}-{:08x}-{}", std::process::id(), count, nanos, suffix));
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                if let Err(e) = file.write_all(contents) {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(Error::io(path.display().to_string(), e))
+                }
+                return Ok(path)
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(Error::io(path.display().to_string(), e)),
+        }
+    }
+}
+
+/// Uncompressed SAM or BAM read from stdin, e.g. straight from minimap2.
+/// Records may come in any order
+pub enum StdinReader {
+    Sam(bam::SamReader<io::BufReader<io::Stdin>>),
+    Bam(bam::BamReader<io::BufReader<io::Stdin>>),
+}
+
+impl StdinReader {
+    /// BAM is recognised by its (bgzip) magic number, anything else is read as SAM
+    pub fn open(threads: usize) -> Result<StdinReader> {
+        let mut stdin = io::BufReader::new(io::stdin());
+        let is_bam = stdin.fill_buf().map_err(|e| Error::io("stdin", e))?.starts_with(&GZIP_MAGIC);
+        if is_bam {
+            let reader = bam::BamReader::from_stream(stdin, threads.saturating_sub(1) as u16)
+                .map_err(|e| Error::io("stdin", e))?;
+            return Ok(StdinReader::Bam(reader))
+        }
+        let reader = bam::SamReader::from_stream(stdin).map_err(|e| Error::io("stdin", e))?;
+        Ok(StdinReader::Sam(reader))
+    }
+
+    pub fn header(&self) -> &bam::Header {
+        match self {
+            StdinReader::Sam(reader) => reader.header(),
+            StdinReader::Bam(reader) => reader.header(),
+        }
+    }
+}
+
+impl Iterator for StdinReader {
+    type Item = io::Result<bam::Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            StdinReader::Sam(reader) => reader.next(),
+            StdinReader::Bam(reader) => reader.next(),
+        }
+    }
+}
+
+/// Compression applied to output files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputCompression {
+    None,
+    Gzip,
+    Bgzip,
+}
+
+impl OutputCompression {
+    /// suffix added to the output file name
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputCompression::None => "",
+            OutputCompression::Gzip | OutputCompression::Bgzip => ".gz",
+        }
+    }
+}
+
+impl FromStr for OutputCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(OutputCompression::None),
+            "gzip" => Ok(OutputCompression::Gzip),
+            "bgzip" => Ok(OutputCompression::Bgzip),
+            _ => Err(format!("unknown compression '{}', expected none, gzip or bgzip", s)),
+        }
+    }
+}
+
+/// Buffered writer for output files that streams to disk as data is written
+pub enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Bgzip(BgzfWriter<BufWriter<File>>),
+}
+
+impl OutputWriter {
+    pub fn create<P: AsRef<Path>>(filename: P, compression: OutputCompression) -> io::Result<OutputWriter> {
+        let file = BufWriter::new(File::create(filename)?);
+        Ok(match compression {
+            OutputCompression::None => OutputWriter::Plain(file),
+            OutputCompression::Gzip => OutputWriter::Gzip(GzEncoder::new(file, Compression::default())),
+            OutputCompression::Bgzip => OutputWriter::Bgzip(BgzfWriter::new(file)),
+        })
+    }
+
+    /// write any buffered data and the compression trailer, if there is one
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut w) => w.flush(),
+            OutputWriter::Gzip(w) => w.finish()?.flush(),
+            OutputWriter::Bgzip(w) => w.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(w) => w.write(buf),
+            OutputWriter::Gzip(w) => w.write(buf),
+            OutputWriter::Bgzip(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(w) => w.flush(),
+            OutputWriter::Gzip(w) => w.flush(),
+            OutputWriter::Bgzip(w) => w.flush(),
+        }
+    }
+}
+
+/// largest amount of uncompressed data in one BGZF block (as used by htslib)
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+/// empty block marking the end of a BGZF file
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Writer for the blocked gzip format (BGZF) read by bgzip, tabix and samtools
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner,
+            buf: Vec::with_capacity(BGZF_BLOCK_SIZE),
+        }
+    }
+
+    /// write remaining data and the end-of-file block, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        Ok(self.inner)
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(())
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buf)?;
+        let data = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+
+        // gzip header with the BC extra field holding the total block size - 1
+        let block_size = (data.len() + 25) as u16;
+        let mut header: [u8; 18] = [0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00, 0, 0];
+        header[16..18].copy_from_slice(&block_size.to_le_bytes());
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&data)?;
+        self.inner.write_all(&crc.sum().to_le_bytes())?;
+        self.inner.write_all(&(self.buf.len() as u32).to_le_bytes())?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), BGZF_BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temp dir, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        /// write `contents` to a file unique to this process and `name`
+        fn new(name: &str, contents: impl AsRef<[u8]>) -> TempFile {
+            let path = std::env::temp_dir().join(format!("hope-test-{}-{}", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> String {
+            self.0.display().to_string()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// the FASTA is loaded, so it can be removed straight away
+    fn bed_assembly(name: &str) -> Assembly {
+        let fasta = TempFile::new(name, ">c1\nACGTCAGGCAAAAATCGTGC\n>track_1\nGTTTTTGA\n>browser2\nCCCCGA\n");
+        Assembly::open(fasta.path()).unwrap()
+    }
+
+    /// line number of a parse error from reading `bed`
+    fn bed_error_line(name: &str, bed: &str) -> usize {
+        let assembly = bed_assembly(&format!("{}.fa", name));
+        let bed = TempFile::new(&format!("{}.bed", name), bed);
+        match read_bed_file(bed.path(), &assembly) {
+            Err(Error::Parse { line, .. }) => line,
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    /// line number and message of the parse error from reading `contents` as a homopolymer file
+    fn homo_file_error(name: &str, contents: &str) -> (usize, String) {
+        let file = TempFile::new(name, contents);
+        match read_homo_pol_file(file.path()) {
+            Err(Error::Parse { line, message, .. }) => (line, message),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn homo_file_coordinates() {
+        let file = TempFile::new("homos.txt", "c1\t10\t14\ta\t5\r\n\nc2\t1\t3\tG\t3\n");
+        let homos = read_homo_pol_file(file.path()).unwrap();
+        let sites: Vec<(&str, u32, u32, &str, u32)> = homos.iter().map(|h| (h.contig.as_str(), h.start, h.stop, h.base.as_str(), h.length)).collect();
+        assert_eq!(sites, vec![("c1", 9, 14, "A", 5), ("c2", 0, 3, "G", 3)]);
+    }
+
+    #[test]
+    fn homo_file_errors() {
+        let file = TempFile::new("bad_start.txt", "c1\t10\t14\tA\t5\nc1\t0\t14\tA\t5\n");
+        let e = read_homo_pol_file(file.path()).unwrap_err();
+        assert_eq!(e.to_string(), format!("{}: line 2: start '0' is not a 1-based coordinate", file.path()));
+
+        assert_eq!(homo_file_error("bad_length.txt", "c1\t10\t14\tA\tfive\n"), (1, "length 'five' is not a number".to_string()));
+        assert_eq!(homo_file_error("bad_base.txt", "c1\t10\t14\tA\t5\nc1\t20\t24\tN\t5\n"), (2, "base 'N' is not one of A, C, G or T".to_string()));
+        assert_eq!(homo_file_error("columns.txt", "c1\t10\t14\tA\n"), (1, "expected 5 tab-separated columns (contig, start, stop, base, length) but found 4".to_string()));
+        assert_eq!(homo_file_error("bad_stop.txt", "c1\t10\t9\tA\t5\n"), (1, "stop '9' is before start '10'".to_string()));
+        assert_eq!(homo_file_error("empty_contig.txt", "\t10\t14\tA\t5\n"), (1, "contig is empty".to_string()));
+    }
+
+    #[test]
+    fn fasta_errors() {
+        for (name, contents) in [("empty.fa", ""), ("blank.fa", "\n\n")] {
+            let file = TempFile::new(name, contents);
+            match read_fasta(file.path()) {
+                Err(Error::Input(message)) => assert_eq!(message, format!("{}: no sequences found, is this a FASTA file?", file.path())),
+                other => panic!("expected an input error, got {:?}", other.map(|_| ())),
+            }
+        }
+        let fasta_error = |name: &str, contents: &str| {
+            let file = TempFile::new(name, contents);
+            match read_fasta(file.path()) {
+                Err(Error::Parse { line, message, .. }) => (line, message),
+                other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+            }
+        };
+        assert_eq!(fasta_error("no_header.fa", "ACGT\n>c1\nACGT\n"), (1, "sequence found before the first '>' header line".to_string()));
+        assert_eq!(fasta_error("no_name.fa", ">c1\nACGT\n> \nACGT\n"), (3, "sequence has no name".to_string()));
+        assert_eq!(fasta_error("repeated.fa", ">c1\nACGT\n>c1 again\nACGT\n"), (3, "sequence c1 appears more than once".to_string()));
+    }
+
+    #[test]
+    fn temp_files_are_unique() {
+        let first = TempFile(create_temp_file("unique.bed", b"c1\t0\t10\n").unwrap());
+        let second = TempFile(create_temp_file("unique.bed", b"c2\t0\t10\n").unwrap());
+        assert_ne!(first.0, second.0);
+        assert_eq!(std::fs::read_to_string(&first.0).unwrap(), "c1\t0\t10\n");
+        assert_eq!(std::fs::read_to_string(&second.0).unwrap(), "c2\t0\t10\n");
+    }
+
+    #[test]
+    fn bed_coordinates_are_0_based() {
+        let assembly = bed_assembly("coordinates.fa");
+        let bed = TempFile::new("coordinates.bed", "c1\t9\t14\nc1\t9\t14\tA\r\nc1\t9\t14\t5A\n");
+        let homos = read_bed_file(bed.path(), &assembly).unwrap();
+        assert_eq!(homos.len(), 3);
+        for homo in &homos {
+            assert_eq!((homo.contig.as_str(), homo.start, homo.stop, homo.base.as_str(), homo.length), ("c1", 9, 14, "A", 5));
+        }
+        // one base too early is not a homopolymer
+        assert_eq!(bed_error_line("off_by_one", "c1\t8\t13\n"), 1);
+    }
+
+    #[test]
+    fn bed_header_lines() {
+        let assembly = bed_assembly("headers.fa");
+        let bed = TempFile::new("headers.bed", "track name=homopolymers\nbrowser position c1:1-20\ntrack\n# comment\n\ntrack_1\t1\t6\nbrowser2\t0\t4\t4C\n");
+        let homos = read_bed_file(bed.path(), &assembly).unwrap();
+        assert_eq!(homos.len(), 2);
+        assert_eq!((homos[0].contig.as_str(), homos[0].start, homos[0].stop, homos[0].base.as_str()), ("track_1", 1, 6, "T"));
+        assert_eq!((homos[1].contig.as_str(), homos[1].start, homos[1].length, homos[1].base.as_str()), ("browser2", 0, 4, "C"));
+    }
+
+    #[test]
+    fn bed_malformed_lines() {
+        assert_eq!(bed_error_line("columns", "c1\t9\t14\nc1\t9\n"), 2);
+        assert_eq!(bed_error_line("start", "track\nc1\tx\t14\n"), 2);
+        assert_eq!(bed_error_line("end", "c1\t9\t9\n"), 1);
+        assert_eq!(bed_error_line("empty_chrom", "\t9\t14\n"), 1);
+        assert_eq!(bed_error_line("name_length", "c1\t9\t14\t4A\n"), 1);
+        assert_eq!(bed_error_line("contig", "c2\t9\t14\n"), 1);
+        assert_eq!(bed_error_line("past_end", "c1\t18\t25\n"), 1);
+    }
+
+    /// `n` bases from a fixed pseudo-random sequence
+    fn random_bases(n: usize, seed: u32) -> String {
+        let mut state = seed;
+        (0..n).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            b"ACGT"[(state >> 16) as usize % 4] as char
+        }).collect()
+    }
+
+    /// `contigs` as a FASTA file with `line_bases` bases a line, and its `.fai`
+    fn fasta_with_index(contigs: &[(&str, &str)], line_bases: usize) -> (String, String) {
+        let (mut fasta, mut fai) = (String::new(), String::new());
+        for (name, seq) in contigs {
+            fasta.push_str(&format!(">{} description\n", name));
+            fai.push_str(&format!("{}\t{}\t{}\t{}\t{}\n", name, seq.len(), fasta.len(), line_bases, line_bases + 1));
+            for line in seq.as_bytes().chunks(line_bases) {
+                fasta.push_str(std::str::from_utf8(line).unwrap());
+                fasta.push('\n');
+            }
+        }
+        (fasta, fai)
+    }
+
+    #[test]
+    fn fai_byte_offsets() {
+        let entry = FaiEntry { name: "c1".to_string(), length: 200, offset: 4, line_bases: 60, line_width: 61 };
+        assert_eq!(entry.byte_offset(0), 4);
+        assert_eq!(entry.byte_offset(59), 63);
+        // the newline ends the first line
+        assert_eq!(entry.byte_offset(60), 65);
+        assert_eq!(entry.byte_offset(125), 4 + 2 * 61 + 5);
+        // windows line endings
+        let entry = FaiEntry { line_width: 62, ..entry };
+        assert_eq!(entry.byte_offset(60), 66);
+        assert_eq!(entry.byte_offset(125), 4 + 2 * 62 + 5);
+    }
+
+    #[test]
+    fn indexed_fasta_fetch() {
+        let (c1, c2) = (random_bases(150, 1), random_bases(37, 2));
+        let (fasta, fai) = fasta_with_index(&[("c1", &c1), ("c2", &c2)], 60);
+        let file = TempFile::new("indexed.fa", fasta);
+        let _fai = TempFile::new("indexed.fa.fai", fai);
+        assert!(IndexedFasta::is_indexed(&file.path()).unwrap());
+        let indexed = IndexedFasta::open(file.path()).unwrap();
+        assert_eq!(indexed.contig_names(), vec!["c1", "c2"]);
+        assert_eq!((indexed.contig_len("c1"), indexed.contig_len("c2"), indexed.contig_len("c3")), (Some(150), Some(37), None));
+
+        // whole contigs, windows within a line, either side of a newline and across several lines
+        for (start, stop) in [(0, 150), (0, 1), (10, 20), (59, 60), (60, 61), (55, 65), (59, 121), (140, 150), (149, 150)] {
+            assert_eq!(indexed.fetch("c1", start, stop).unwrap(), &c1[start as usize..stop as usize], "c1:{}-{}", start, stop);
+        }
+        assert_eq!(indexed.fetch("c2", 0, 37).unwrap(), c2);
+        assert_eq!(indexed.fetch("c2", 20, 20).unwrap(), "");
+        assert!(indexed.fetch("c2", 30, 38).is_err());
+        assert!(indexed.fetch("c3", 0, 1).is_err());
+    }
+
+    #[test]
+    fn bgzipped_fasta_fetch() {
+        // several bgzip blocks
+        let (c1, c2) = (random_bases(150_000, 3), random_bases(70_000, 4));
+        let (fasta, fai) = fasta_with_index(&[("c1", &c1), ("c2", &c2)], 70);
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(fasta.as_bytes()).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        // the .gzi lists the start of every block after the first, leaving out the empty last block
+        let mut block_starts: Vec<(u64, u64)> = Vec::new();
+        let (mut offset, mut uncompressed) = (0, 0);
+        while offset < compressed.len() - BGZF_EOF.len() {
+            let block = &compressed[offset..];
+            let block_size = u16::from_le_bytes([block[16], block[17]]) as usize + 1;
+            block_starts.push((offset as u64, uncompressed));
+            uncompressed += u32::from_le_bytes(block[block_size - 4..block_size].try_into().unwrap()) as u64;
+            offset += block_size;
+        }
+        assert!(block_starts.len() > 2);
+        let mut gzi: Vec<u8> = Vec::new();
+        gzi.extend(((block_starts.len() - 1) as u64).to_le_bytes());
+        for (compressed, uncompressed) in &block_starts[1..] {
+            gzi.extend(compressed.to_le_bytes());
+            gzi.extend(uncompressed.to_le_bytes());
+        }
+
+        let file = TempFile::new("indexed.fa.gz", &compressed);
+        let _fai = TempFile::new("indexed.fa.gz.fai", fai);
+        assert!(!IndexedFasta::is_indexed(&file.path()).unwrap());
+        let gzi = TempFile::new("indexed.fa.gz.gzi", gzi);
+        assert!(IndexedFasta::is_indexed(&file.path()).unwrap());
+        assert_eq!(read_gzi(&gzi.path()).unwrap(), block_starts);
+        let indexed = IndexedFasta::open(file.path()).unwrap();
+
+        // windows either side of and across each block boundary
+        let c1_offset = (">c1 description\n".len()) as u64;
+        let mut windows: Vec<(u32, u32)> = vec![(0, 150_000), (0, 10), (149_990, 150_000)];
+        for &(_, uncompressed) in &block_starts[1..] {
+            let bytes = uncompressed - c1_offset;
+            let pos = (bytes / 71 * 70 + (bytes % 71).min(70)) as u32;
+            if pos >= 10 && pos + 10 <= 150_000 {
+                windows.extend([(pos - 10, pos), (pos, pos + 10), (pos - 10, pos + 10), (pos - 1, pos + 1)]);
+            }
+        }
+        assert!(windows.len() > 3);
+        for &(start, stop) in &windows {
+            assert_eq!(indexed.fetch("c1", start, stop).unwrap(), &c1[start as usize..stop as usize], "c1:{}-{}", start, stop);
+        }
+        assert_eq!(indexed.fetch("c2", 0, 70_000).unwrap(), c2);
+
+        // threads share the file
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (indexed, c1, windows) = (&indexed, &c1, &windows);
+                scope.spawn(move || {
+                    for &(start, stop) in windows.iter().cycle().skip(thread).take(50) {
+                        assert_eq!(indexed.fetch("c1", start, stop).unwrap(), &c1[start as usize..stop as usize]);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn gzi_must_hold_every_entry() {
+        let mut gzi: Vec<u8> = Vec::new();
+        gzi.extend(2u64.to_le_bytes());
+        gzi.extend(100u64.to_le_bytes());
+        gzi.extend(65_280u64.to_le_bytes());
+        let file = TempFile::new("short.gzi", &gzi);
+        assert!(read_gzi(&file.path()).is_err());
+        let file = TempFile::new("empty.gzi", 0u64.to_le_bytes());
+        assert_eq!(read_gzi(&file.path()).unwrap(), vec![(0, 0)]);
+    }
+
+    /// the problems listed by `check_references`
+    fn contig_problems(references: &[(&str, u32)], homo_contigs: &[&str]) -> Vec<String> {
+        let fasta = TempFile::new("contigs.fa", ">c1\nACGTCAGGCAAAAATCGTGC\n>c2\nGTTTTTGA\n");
+        let assembly = Assembly::open(fasta.path()).unwrap();
+        match check_references(references, &assembly, homo_contigs) {
+            Ok(()) => Vec::new(),
+            Err(Error::Input(message)) => message.lines().skip(1).map(|line| line.trim().to_string()).collect(),
+            Err(e) => panic!("expected an input error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn contigs_must_match() {
+        assert!(contig_problems(&[("c1", 20), ("c2", 8)], &["c1", "c2"]).is_empty());
+        // contigs without homopolymers may be missing from either
+        assert!(contig_problems(&[("c1", 20), ("c9", 100)], &["c1"]).is_empty());
+        assert_eq!(contig_problems(&[("c1", 20), ("c3", 5)], &["c1", "c3"]), vec!["contig c3 has homopolymers but is not in the assembly"]);
+        assert_eq!(contig_problems(&[("c1", 20)], &["c1", "c2"]), vec!["contig c2 has homopolymers but is not in the bam header"]);
+        assert_eq!(contig_problems(&[("c1", 20), ("c2", 9)], &["c1"]), vec!["contig c2 is 9 bases long in the bam header but 8 bases long in the assembly"]);
+        // every problem is listed
+        assert_eq!(contig_problems(&[("c1", 21)], &["c1", "c4"]), vec![
+            "contig c4 has homopolymers but is not in the assembly",
+            "contig c4 has homopolymers but is not in the bam header",
+            "contig c1 is 21 bases long in the bam header but 20 bases long in the assembly",
+        ]);
+    }
+
+    #[test]
+    fn bgzf_round_trip() {
+        // more than 64 KiB, so several blocks are written
+        let mut data: Vec<u8> = Vec::new();
+        let mut state: u32 = 1;
+        while data.len() < 200_000 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            data.push(b"ACGT\t\n"[(state >> 16) as usize % 6]);
+        }
+        let mut writer = BgzfWriter::new(Vec::new());
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        let mut decoded: Vec<u8> = Vec::new();
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, data);
+        assert!(compressed.ends_with(&BGZF_EOF));
+
+        // each block's BSIZE leads to the start of the next, and none holds more than a block of data
+        let mut offset = 0;
+        let mut blocks = 0;
+        let mut total = 0;
+        while offset < compressed.len() {
+            let block = &compressed[offset..];
+            assert_eq!(&block[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+            assert_eq!(&block[12..14], b"BC");
+            let block_size = u16::from_le_bytes([block[16], block[17]]) as usize + 1;
+            let isize = u32::from_le_bytes(block[block_size - 4..block_size].try_into().unwrap()) as usize;
+            assert!(isize <= BGZF_BLOCK_SIZE);
+            total += isize;
+            offset += block_size;
+            blocks += 1;
+        }
+        assert_eq!(offset, compressed.len());
+        assert_eq!(total, data.len());
+        // the data blocks and the empty end-of-file block
+        assert_eq!(blocks, data.len().div_ceil(BGZF_BLOCK_SIZE) + 1);
     }
 }
@@ -0,0 +1,37 @@
+//! hope (homopolymer performance). Identify portions of long reads that map to
+//! specified homopolymers in an assembly and score errors in the sequencing of
+//! those homopolymers.
+//!
+//! ```no_run
+//! use hope::{HomopolymerIndex, ScoredReads};
+//!
+//! let assembly = hope::Assembly::open("assembly.fasta".to_string()).unwrap();
+//! let homos = HomopolymerIndex::new(hope::read_homo_pol_file("homopolymers.txt".to_string()).unwrap());
+//! let reader = bam::BamReader::from_path("reads.bam", 0).unwrap();
+//! let header = reader.header().clone();
+//! for read in ScoredReads::new(reader, &header, &homos, &assembly) {
+//!     let read = read.unwrap();
+//!     for hr in read.results() {
+//!         println!("{}\t{}\t{}", read.ra.name, hr.homo.start, hr.score);
+//!     }
+//! }
+//! ```
+
+pub mod confusion;
+pub mod control;
+pub mod error;
+pub mod homopolymer;
+pub mod io;
+pub mod plot;
+pub mod read_alignment;
+pub mod report;
+pub mod scoring;
+pub mod summary;
+
+pub use confusion::ConfusionMatrix;
+pub use error::{Error, Result};
+pub use homopolymer::{find_homopolymers, FlankLengths, HomopolymerIndex, HomopolymerRecord, HomopolymerRegion, HomopolymerResult, HomopolymerScore};
+pub use io::{read_bed_file, read_fasta, read_homo_pol_file, read_homopolymers, write_homo_pol_file, Assembly, FastaSequence, HomopolymerFormat, IndexedFasta};
+pub use read_alignment::{AlignmentProjection, CigarOp, OtherAlignment, ReadAlignment};
+pub use scoring::{FilterCounts, FilterReason, ReadFilter, ReadOutcome, ScoredRead, ScoredReads};
+pub use summary::{SiteSummaries, SiteSummary};
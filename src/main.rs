@@ -1,124 +1,640 @@
-use clap::Parser;
-use bam;
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Range;
 
-mod homopolymer;
-mod io;
-mod read_alignment;
+use clap::{Parser, Subcommand};
 
+use hope::confusion::{self, ConfusionMatrix};
+use hope::control::{find_control_regions, ControlIndex, ControlResult};
+use hope::io;
+use hope::plot;
+use hope::report::Report;
+use hope::scoring::{FilterCounts, ReadFilter, ReadOutcome, ScoredRead};
+use hope::summary::{self, SiteSummaries, NO_READ_GROUP};
+use hope::{Error, FlankLengths, HomopolymerIndex, HomopolymerRecord, HomopolymerScore, HomopolymerRegion, ReadAlignment, Result};
+
+mod parallel;
+
+/// homopolymers closer together than this are fetched from the bam in one query
+const REGION_GAP: u32 = 10_000;
+/// regions are split at this length so that long contigs can be scored by several threads
+const REGION_MAX_SPAN: u32 = 100_000;
+/// number of reads handed to a scoring thread at a time
+const BATCH_SIZE: usize = 64;
 
 /// hope (homopolymer performance). Identify portions of long reads that map to
 /// specified homopolymers in an assembly. Report errors in the sequencing of
 /// those homopolymers
 #[derive(Parser)]
 #[clap(version = "0.2.4", author = "Alan Collins <Alan.Collins@IHRC.com>")]
+#[clap(subcommand_negates_reqs = true)]
 struct Opts {
     /// file with homopolymer locations and bases
+    #[clap(short, long, required_unless_present = "discover")]
+    input_homos: Option<String>,
+    /// format of the input-homos file: tsv or bed [default: bed if the file name ends in .bed, otherwise tsv]
+    #[clap(long)]
+    homos_format: Option<io::HomopolymerFormat>,
+    /// find homopolymers in the assembly instead of reading them from a file
+    #[clap(long, conflicts_with = "input-homos")]
+    discover: bool,
+    /// minimum homopolymer length to report when using --discover
+    #[clap(long, default_value = "5")]
+    min_length: u32,
+    /// maximum homopolymer length to report when using --discover
+    #[clap(long)]
+    max_length: Option<u32>,
+    /// the input assembly file
+    #[clap(short, long, required = true)]
+    assembly: Option<String>,
+    /// the input bam or cram file, or - to read sam or bam from stdin. Repeat to score several samples
+    #[clap(short, long, required_unless_present = "samples")]
+    bam: Vec<String>,
+    /// tab-delimited file of sample names and their bam or cram files
+    #[clap(long, conflicts_with = "bam")]
+    samples: Option<String>,
+    /// the outprefix
+    #[clap(short, long, required = true)]
+    outprefix: Option<String>,
+    /// include sequence context in outfile?
     #[clap(short, long)]
-    input_homos: String,
+    context: bool,
+    /// bases either side of a homopolymer checked when scoring it
+    #[clap(long, default_value = "30")]
+    flank: u32,
+    /// bases either side of a homopolymer included in the context columns
+    #[clap(long, default_value = "30")]
+    context_length: u32,
+    /// compress the outfile: none, gzip or bgzip
+    #[clap(long, default_value = "none")]
+    compression: io::OutputCompression,
+    /// skip reads with a lower mapping quality
+    #[clap(long, default_value = "0")]
+    min_mapq: u8,
+    /// skip reads with fewer bases aligned to the assembly
+    #[clap(long, default_value = "0")]
+    min_aligned_length: u32,
+    /// skip reads with a larger fraction of soft clipped bases
+    #[clap(long, default_value = "1.0")]
+    max_soft_clip: f64,
+    /// skip reads with a lower gap-compressed identity to the assembly
+    #[clap(long, default_value = "0.0")]
+    min_identity: f64,
+    /// score supplementary alignments as well as primary ones
+    #[clap(long)]
+    supplementary: bool,
+    /// skip supplementary alignments with a lower mapping quality
+    #[clap(long, default_value = "0", requires = "supplementary")]
+    supplementary_min_mapq: u8,
+    /// also write difference histograms and a heatmap as SVG files
+    #[clap(long)]
+    plot: bool,
+    /// also write a self-contained HTML report of the run
+    #[clap(long)]
+    report: bool,
+    /// number of threads for bam decompression and scoring
+    #[clap(short, long, default_value = "1")]
+    threads: usize,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// find homopolymers in an assembly and write them in the input-homos format
+    Find(FindOpts),
+    /// plot the differences in an output file as SVG histograms and a heatmap
+    Plot(PlotOpts),
+    /// measure the length error of reads in control regions between the homopolymers
+    Control(ControlOpts),
+}
+
+#[derive(Parser)]
+struct FindOpts {
+    /// the input assembly file
+    #[clap(short, long)]
+    assembly: String,
+    /// the outprefix
+    #[clap(short, long)]
+    outprefix: String,
+    /// minimum homopolymer length to report
+    #[clap(long, default_value = "5")]
+    min_length: u32,
+    /// maximum homopolymer length to report
+    #[clap(long)]
+    max_length: Option<u32>,
+}
+
+#[derive(Parser)]
+struct ControlOpts {
     /// the input assembly file
     #[clap(short, long)]
     assembly: String,
-    /// the input bam file
+    /// the input bam or cram file, or - to read sam or bam from stdin
     #[clap(short, long)]
     bam: String,
+    /// file with homopolymer locations and bases
+    #[clap(short, long)]
+    input_homos: String,
+    /// format of the input-homos file: tsv or bed [default: bed if the file name ends in .bed, otherwise tsv]
+    #[clap(long)]
+    homos_format: Option<io::HomopolymerFormat>,
     /// the outprefix
     #[clap(short, long)]
     outprefix: String,
-    /// include sequence context in outfile?
+    /// length of the non-homopolymer control regions
+    #[clap(short, long, default_value = "10")]
+    length: u32,
+    /// minimum distance from a control region to the nearest homopolymer
+    #[clap(short, long, default_value = "10")]
+    pad: u32,
+    /// number of threads for bam decompression
+    #[clap(short, long, default_value = "1")]
+    threads: usize,
+}
+
+#[derive(Parser)]
+struct PlotOpts {
+    /// an output file written by hope
     #[clap(short, long)]
-    context: bool,
+    input: String,
+    /// the outprefix
+    #[clap(short, long)]
+    outprefix: String,
+}
+
+/// What a scoring thread hands back for one batch of reads
+#[derive(Default)]
+struct BatchResult<'a> {
+    lines: Vec<String>,
+    counts: FilterCounts,
+    /// score of each homopolymer in each read, with the read's read group
+    scores: Vec<(&'a HomopolymerRecord, Option<String>, HomopolymerScore)>,
 }
 
+/// Where the reads are read from
+enum Alignments {
+    Bam(bam::IndexedReader<std::fs::File>),
+    Cram(io::CramReader),
+    /// sam or bam piped in, in any order
+    Stdin(io::StdinReader),
+}
+
+impl Alignments {
+    /// `-` is stdin, CRAM is recognised from the file contents
+    fn open(bam: &str, assembly_path: &str, regions: &[HomopolymerRegion], threads: usize) -> Result<Alignments> {
+        if bam == "-" {
+            return Ok(Alignments::Stdin(io::StdinReader::open(threads)?))
+        }
+        if io::is_cram(bam)? {
+            return Ok(Alignments::Cram(io::CramReader::open(bam, assembly_path, regions, threads)?))
+        }
+        let reader = bam::IndexedReader::build()
+            .additional_threads((threads - 1) as u16)
+            .from_path(bam)
+            .map_err(|e| Error::io(bam, e))?;
+        Ok(Alignments::Bam(reader))
+    }
+
+    fn header(&self) -> &bam::Header {
+        match self {
+            Alignments::Bam(reader) => reader.header(),
+            Alignments::Cram(reader) => reader.header(),
+            Alignments::Stdin(reader) => reader.header(),
+        }
+    }
+}
 
 fn main() {
     let args = Opts::parse();
-    let homos = io::read_homo_pol_file(args.input_homos);
-    
-    let fasta_seq = io::read_fasta(args.assembly);
+    let result = match args.command {
+        Some(Command::Find(find_args)) => find(find_args),
+        Some(Command::Plot(plot_args)) => plot(plot_args),
+        Some(Command::Control(control_args)) => control(control_args),
+        None => score(args),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut outlines: Vec<String> = Vec::new();
-    if args.context {
-        outlines.push("homopolymer_length\thomopolymer_base\tdifference\tread_context\tassembly_context\thomo_start\tread_ID\n".to_string());
+fn find(args: FindOpts) -> Result<()> {
+    let assembly = io::Assembly::open(args.assembly)?;
+    let homos = assembly.find_homopolymers(args.min_length, args.max_length)?;
+    let outfile = format!("{}{}", args.outprefix, "homopolymers.txt");
+    io::write_homo_pol_file(outfile, &homos)
+}
+
+fn plot(args: PlotOpts) -> Result<()> {
+    let matrix = io::read_confusion_matrix(args.input)?;
+    plot::write_plots(&matrix, &args.outprefix)?;
+    Ok(())
+}
+
+fn control(args: ControlOpts) -> Result<()> {
+    if args.length == 0 {
+        return Err(Error::Input("--length must be at least 1".to_string()))
+    }
+    let assembly = io::Assembly::open(args.assembly.clone())?;
+    let homos = io::read_homopolymers(args.input_homos.clone(), args.homos_format, &assembly)?;
+    if homos.is_empty() {
+        return Err(Error::Input(format!("{}: no homopolymers found", args.input_homos)))
+    }
+    let homos = HomopolymerIndex::new(homos);
+    let controls = ControlIndex::new(find_control_regions(&homos, &assembly, args.length, args.pad));
+    if controls.is_empty() {
+        return Err(Error::Input(format!("no gaps between homopolymers leave room for a {} base control region {} bases from each", args.length, args.pad)))
+    }
+
+    let outfile = format!("{}control_out.txt", args.outprefix);
+    let write_err = |e| Error::io(&outfile, e);
+    let mut writer = io::OutputWriter::create(&outfile, io::OutputCompression::None).map_err(write_err)?;
+    writer.write_all(b"ref_length\tread_length\tdifference\tread_sequence\tref_sequence\tregion_contig\tregion_start\tread_id\n").map_err(write_err)?;
+
+    let threads = std::cmp::max(args.threads, 1);
+    let homo_contigs: Vec<&str> = homos.contigs().iter().map(|c| c.as_str()).collect();
+    let bam = &args.bam;
+    // each read is read once, so no index is needed
+    if bam == "-" {
+        let mut reader = io::StdinReader::open(threads)?;
+        io::check_contigs(reader.header(), &assembly, &homo_contigs)?;
+        let ref_names = reader.header().reference_names().to_vec();
+        score_controls(&mut reader, &ref_names, &controls, &assembly, bam, &mut writer, &outfile)?;
+    } else if io::is_cram(bam)? {
+        let mut reader = io::CramReader::open(bam, &args.assembly, &controls.regions(), threads)?;
+        io::check_contigs(reader.header(), &assembly, &homo_contigs)?;
+        let ref_names = reader.header().reference_names().to_vec();
+        score_controls(reader.records(), &ref_names, &controls, &assembly, bam, &mut writer, &outfile)?;
+        reader.finish()?;
     } else {
-        outlines.push("homopolymer_length\thomopolymer_base\tdifference\thomo_start\tread_ID\n".to_string());
+        let mut reader = bam::BamReader::from_path(bam, (threads - 1) as u16).map_err(|e| Error::io(bam, e))?;
+        io::check_contigs(reader.header(), &assembly, &homo_contigs)?;
+        let ref_names = reader.header().reference_names().to_vec();
+        score_controls(&mut reader, &ref_names, &controls, &assembly, bam, &mut writer, &outfile)?;
     }
+    writer.finish().map_err(write_err)
+}
 
-    let reader = bam::BamReader::from_path(args.bam, 0).unwrap();
-    for record in reader {
-        let record = record.unwrap();
-        // skip if unmapped or if map is secondary or supplementary
-        if !record.flag().is_mapped() | record.flag().is_secondary() | record.flag().is_supplementary() {
-            continue
-        }
+fn score(args: Opts) -> Result<()> {
+    // with no flank every site would be skipped
+    if args.flank == 0 {
+        return Err(Error::Input("--flank must be at least 1".to_string()))
+    }
+    let mut report = args.report.then(|| Report::new(report_parameters(&args)));
 
-        // extract read name
-        let name: &str = std::str::from_utf8(&record.name()).unwrap();
-        
-        // extract cigar string as vector of tuples
-        let mut cig: Vec<(String, u32)> = Vec::new();
-        for (l, c) in record.cigar().iter() {
-            cig.push((c.to_string(), l));
-        }
+    // clap enforces these unless a subcommand was given
+    let assembly_path = args.assembly.unwrap();
+    let outprefix = args.outprefix.unwrap();
 
-        // extract basic details
-        let contig_id: i32 = record.ref_id();
-
-        // following line doesn't work directly for some reason. Need to split in two
-        // let seq: &str = std::str::from_utf8(&record.sequence().to_vec()).unwrap();
-
-        // This seems to be the same thing, but works. 
-        let temp = &record.sequence().to_vec();
-        let seq: &str = std::str::from_utf8(&temp).unwrap();
-
-        let contig = fasta_seq.seq_idxs.get(&contig_id).unwrap();
-        let ref_seq = fasta_seq.seq_map.get(&contig.to_string()).unwrap();
-        let start = record.start();
-        let end = record.calculate_end();
-        let flag = record.flag().0;
-
-        let mut ra = read_alignment::ReadAlignment { 
-            cig: cig,
-            contig: contig.to_string(),
-            contig_id: contig_id,
-            seq: seq.to_string(),
-            pos: start,
-            end: end,
-            aligned_end: 0,
-            name: name.to_string(),
-            flag: flag,
-        };
-        ra.aligned_end = ra.get_aligned_index(ra.end as u32) as i32;
-        // ra.generate_alignment(ref_seq);
+    let samples = match args.samples {
+        Some(sample_sheet) => io::read_sample_sheet(sample_sheet)?,
+        None => args.bam.iter().map(|bam| io::Sample::from_path(bam)).collect(),
+    };
+    io::check_samples(&samples)?;
+
+    let assembly = io::Assembly::open(assembly_path.clone())?;
 
-        for homo in &homos {
-            // if read doesn't map to homopolymer, skip
-            if ra.contig != homo.contig{
-                continue
+    let homos = match args.input_homos {
+        Some(input_homos) => {
+            let homos = io::read_homopolymers(input_homos.clone(), args.homos_format, &assembly)?;
+            if homos.is_empty() {
+                return Err(Error::Input(format!("{}: no homopolymers found", input_homos)))
             }
-            if ra.pos as u32 > homo.start {
-                continue
-            } 
-            if homo.stop > ra.end as u32 {
-                continue
+            homos
+        }
+        None => {
+            let homos = assembly.find_homopolymers(args.min_length, args.max_length)?;
+            if homos.is_empty() {
+                return Err(Error::Input(format!("no homopolymers of at least {} bases found in the assembly", args.min_length)))
             }
+            homos
+        }
+    };
 
-            let hr = homopolymer::HomopolymerResult::new(&homo, &ra, &ref_seq);
+    let threads = std::cmp::max(args.threads, 1);
+    let homos = HomopolymerIndex::new(homos);
+    let regions = homos.regions(REGION_GAP, REGION_MAX_SPAN);
+    let contig_regions = contig_regions(&regions);
 
-            let score = match hr.score {
-                homopolymer::HomopolymerScore::Other(score) => score,
-                homopolymer::HomopolymerScore::Difference(score) => score.to_string(),
+    let filter = ReadFilter {
+        min_mapq: args.min_mapq,
+        min_aligned_length: args.min_aligned_length,
+        max_soft_clip: args.max_soft_clip,
+        min_identity: args.min_identity,
+        supplementary: args.supplementary,
+        min_supplementary_mapq: args.supplementary_min_mapq,
+    };
+    if !(0.0..=1.0).contains(&filter.max_soft_clip) || !(0.0..=1.0).contains(&filter.min_identity) {
+        return Err(Error::Input("--max-soft-clip and --min-identity must be between 0 and 1".to_string()))
+    }
+
+    let flanks = FlankLengths { flank: args.flank, context: args.context_length };
+
+    // every sample is opened and checked before any is scored, so that a
+    // missing index or contig is reported before any output is written
+    let homo_contigs: Vec<&str> = homos.contigs().iter().map(|c| c.as_str()).collect();
+    let mut sample_alignments: Vec<Alignments> = Vec::with_capacity(samples.len());
+    for sample in &samples {
+        let alignments = Alignments::open(&sample.path, &assembly_path, &regions, threads)?;
+        // contigs are matched by name, so the bam and assembly must agree on them
+        io::check_contigs(alignments.header(), &assembly, &homo_contigs)?;
+        sample_alignments.push(alignments);
+    }
+
+    let outfile = format!("{}out.txt{}", outprefix, args.compression.extension());
+    let write_err = |e| Error::io(&outfile, e);
+    let mut writer = io::OutputWriter::create(&outfile, args.compression).map_err(write_err)?;
+    let mut header = if args.context {
+        String::from("homopolymer_length\thomopolymer_base\tdifference\tread_context\tassembly_context\thomo_start\tread_ID\tread_group")
+    } else {
+        String::from("homopolymer_length\thomopolymer_base\tdifference\thomo_start\tread_ID\tread_group")
+    };
+    if args.supplementary {
+        header.push_str("\talignment");
+    }
+    // a single bam is still named, so runs can be concatenated
+    header.push_str("\tsample\n");
+    writer.write_all(header.as_bytes()).map_err(write_err)?;
+
+    // per-site summaries are built in the same pass
+    let sites_file = format!("{}sites.tsv", outprefix);
+    let sites_err = |e| Error::io(&sites_file, e);
+    let mut sites_writer = io::OutputWriter::create(&sites_file, io::OutputCompression::None).map_err(sites_err)?;
+    sites_writer.write_all(summary::sites_header(true).as_bytes()).map_err(sites_err)?;
+
+    // samples are scored one after another, sharing the assembly and homopolymers
+    let mut sample_matrices: Vec<(&str, ConfusionMatrix)> = Vec::new();
+    for (sample, alignments) in samples.iter().zip(sample_alignments) {
+        let bam = &sample.path;
+
+        // reads are handed to the scoring threads in batches, tagged with the index of the region they were fetched for
+        let produce = |submit: &mut dyn FnMut(Vec<(usize, bam::Record)>) -> Result<()>| -> Result<()> {
+            let mut batch: Vec<(usize, bam::Record)> = Vec::with_capacity(BATCH_SIZE);
+            let mut add = |item: (usize, bam::Record)| -> Result<()> {
+                batch.push(item);
+                if batch.len() == BATCH_SIZE {
+                    submit(std::mem::take(&mut batch))?;
+                }
+                Ok(())
             };
+            match alignments {
+                Alignments::Bam(mut reader) => {
+                    for (region_idx, region) in regions.iter().enumerate() {
+                        let ref_id = reader.header().reference_id(&region.contig).unwrap();
+
+                        // only reads overlapping this region are decoded
+                        let bam_region = bam::Region::new(ref_id, region.start, region.stop);
+                        for record in reader.fetch(&bam_region).map_err(|e| Error::io(bam, e))? {
+                            let record = record.map_err(|e| Error::io(bam, e))?;
+                            if !filter.accepts(&record) {
+                                continue
+                            }
+                            add((region_idx, record))?;
+                        }
+                    }
+                }
+                Alignments::Cram(mut reader) => {
+                    let ref_names = reader.header().reference_names().to_vec();
+                    add_by_region(reader.records(), &ref_names, &regions, &contig_regions, &filter, bam, &mut add)?;
+                    reader.finish()?;
+                }
+                Alignments::Stdin(mut reader) => {
+                    let ref_names = reader.header().reference_names().to_vec();
+                    add_by_region(&mut reader, &ref_names, &regions, &contig_regions, &filter, bam, &mut add)?;
+                }
+            }
+            if !batch.is_empty() {
+                submit(batch)?;
+            }
+            Ok(())
+        };
+
+        let work = |batch: Vec<(usize, bam::Record)>| -> Result<BatchResult> {
+            let mut result = BatchResult::default();
+            for (region_idx, record) in batch {
+                let region = &regions[region_idx];
+                let record_start = record.start() as u32;
+                let record_end = record.calculate_end() as u32;
+                // a read overlapping several regions is scored in each only for that region's homopolymers
+                let covered: Vec<&HomopolymerRecord> = region_homopolymers(&homos, region, record_start, record_end).collect();
+                // and counted only in the first of those regions where it covers a homopolymer
+                let first = |ra: &ReadAlignment| overlapping_regions(&regions, &contig_regions, &region.contig, record_start, record_end)
+                    .take_while(|&other_idx| other_idx < region_idx)
+                    .all(|other_idx| !region_homopolymers(&homos, &regions[other_idx], record_start, record_end).any(|homo| ra.counts_site(homo.start, homo.stop)));
+                let read = match ScoredRead::from_record(&record, &region.contig, covered, &assembly, &filter, flanks)? {
+                    ReadOutcome::NotCovered => continue,
+                    ReadOutcome::Filtered(ra, reason) => {
+                        if first(&ra) {
+                            result.counts.count(Some(reason));
+                        }
+                        continue
+                    }
+                    ReadOutcome::Scored(read) => {
+                        if first(&read.ra) {
+                            result.counts.count(None);
+                        }
+                        read
+                    }
+                };
+                let read_group = read.ra.read_group.as_deref().unwrap_or(NO_READ_GROUP);
+                for (homo, hr) in read.homos.iter().zip(read.results()) {
+                    let mut line = if args.context {
+                        format!("{0}\t{1}\t{2}\t{3}\t{4}\t{5}\t{6}\t{7}", hr.homo_length, hr.base, hr.score, &hr.region_read_aln, &hr.region_ref_aln, hr.homo.start, hr.ra.name, read_group)
+                    } else {
+                        format!("{0}\t{1}\t{2}\t{3}\t{4}\t{5}", hr.homo_length, hr.base, hr.score, hr.homo.start, hr.ra.name, read_group)
+                    };
+                    if args.supplementary {
+                        line.push_str(if read.ra.is_supplementary() { "\tsupplementary" } else { "\tprimary" });
+                    }
+                    line.push('\t');
+                    line.push_str(&sample.name);
+                    line.push('\n');
+                    result.lines.push(line);
+                    result.scores.push((homo, read.ra.read_group.clone(), hr.score));
+                }
+            }
+            Ok(result)
+        };
+
+        // lines are written as soon as their batch is scored
+        let mut sample_counts = FilterCounts::default();
+        let mut sites = SiteSummaries::default();
+        let mut matrix = ConfusionMatrix::default();
+        parallel::ordered_map(threads, produce, work, |result| {
+            let result = result?;
+            for line in result.lines {
+                writer.write_all(line.as_bytes()).map_err(write_err)?;
+            }
+            sample_counts.add(&result.counts);
+            for (homo, read_group, score) in &result.scores {
+                sites.add(homo, read_group.as_deref(), score);
+                matrix.add(homo, score);
+            }
+            Ok(())
+        })?;
+        eprintln!("{}: {}", sample.name, sample_counts);
+        sites.write_rows(&homos, Some(sample.name.as_str()), &mut sites_writer).map_err(sites_err)?;
+        if let Some(report) = report.as_mut() {
+            report.add_sample(&sample.name, sample_counts, &sites, &homos, &matrix);
+        }
+        sample_matrices.push((&sample.name, matrix));
+    }
+    sites_writer.finish().map_err(sites_err)?;
+
+    let matrices: Vec<(Option<&str>, &ConfusionMatrix)> = sample_matrices.iter().map(|(name, matrix)| (Some(*name), matrix)).collect();
+    write_report(&format!("{}confusion.tsv", outprefix), |w| confusion::write_tsv(&matrices, false, w))?;
+    write_report(&format!("{}confusion_proportions.tsv", outprefix), |w| confusion::write_tsv(&matrices, true, w))?;
+    write_report(&format!("{}confusion.json", outprefix), |w| confusion::write_json(&matrices, w))?;
+    if let Some(report) = report {
+        write_report(&format!("{}report.html", outprefix), |w| report.write(w))?;
+    }
+    if args.plot {
+        // each sample is plotted separately
+        for (sample, matrix) in &sample_matrices {
+            plot::write_plots(matrix, &format!("{}{}_", outprefix, sample))?;
+        }
+    }
+    writer.finish().map_err(write_err)
+}
 
-            if args.context {
-                outlines.push(format!("{0}\t{1}\t{2}\t{3}\t{4}\t{5}\t{6}\n", hr.homo_length, hr.base, score, &hr.region_read_aln, &hr.region_ref_aln, hr.homo.start, hr.ra.name));
-            } else {
-                outlines.push(format!("{0}\t{1}\t{2}\t{3}\t{4}\n", hr.homo_length, hr.base, score, hr.homo.start, hr.ra.name));
+/// Write a line for every primary alignment spanning each control region, in
+/// the order the reads are read
+fn score_controls<I>(records: I, ref_names: &[String], controls: &ControlIndex, assembly: &io::Assembly, bam: &str, writer: &mut io::OutputWriter, outfile: &str) -> Result<()>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    let filter = ReadFilter::default();
+    for record in records {
+        let record = record.map_err(|e| Error::io(bam, e))?;
+        if !filter.accepts(&record) {
+            continue
+        }
+        let contig = &ref_names[record.ref_id() as usize];
+        let spanned = controls.spanned_by(contig, record.start() as u32, record.calculate_end() as u32);
+        if spanned.is_empty() {
+            continue
+        }
+        let ra = ReadAlignment::from_record(&record, contig)?;
+        let window_start = ra.pos as u32;
+        let window = assembly.fetch(contig, window_start, ra.end as u32)?;
+        let proj = ra.project_window(&window, window_start);
+        for region in spanned {
+            if let Some(result) = ControlResult::new(region, &proj) {
+                let line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    result.ref_length(), result.read_length, result.difference(), result.read_alignment, result.ref_alignment, region.contig, region.start, ra.name);
+                writer.write_all(line.as_bytes()).map_err(|e| Error::io(outfile, e))?;
             }
-            
-            
         }
     }
-    let outcontents = outlines.join("");
-    let outfile = format!("{}{}", args.outprefix, "out.txt");
-    std::fs::write(outfile, outcontents).expect("Unable to write file");
+    Ok(())
+}
+
+/// The options a run was given, as listed in the HTML report
+fn report_parameters(args: &Opts) -> Vec<(String, String)> {
+    let mut parameters = vec![
+        ("hope version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ("command".to_string(), std::env::args().collect::<Vec<String>>().join(" ")),
+        ("assembly".to_string(), args.assembly.clone().unwrap_or_default()),
+    ];
+    parameters.push(match &args.input_homos {
+        Some(input_homos) => ("homopolymers".to_string(), input_homos.clone()),
+        None => ("homopolymers".to_string(), match args.max_length {
+            Some(max_length) => format!("discovered, {} to {} bases", args.min_length, max_length),
+            None => format!("discovered, at least {} bases", args.min_length),
+        }),
+    });
+    parameters.push(match &args.samples {
+        Some(sample_sheet) => ("sample sheet".to_string(), sample_sheet.clone()),
+        None => ("bam".to_string(), args.bam.join(", ")),
+    });
+    parameters.extend([
+        ("flank".to_string(), args.flank.to_string()),
+        ("context length".to_string(), args.context_length.to_string()),
+        ("min mapq".to_string(), args.min_mapq.to_string()),
+        ("min aligned length".to_string(), args.min_aligned_length.to_string()),
+        ("max soft clip".to_string(), args.max_soft_clip.to_string()),
+        ("min identity".to_string(), args.min_identity.to_string()),
+    ]);
+    parameters.push(("supplementary alignments".to_string(), if args.supplementary { "scored" } else { "skipped" }.to_string()));
+    if args.supplementary {
+        parameters.push(("supplementary min mapq".to_string(), args.supplementary_min_mapq.to_string()));
+    }
+    parameters.push(("threads".to_string(), args.threads.to_string()));
+    parameters
+}
+
+/// Write a report that is only produced once every sample has been scored
+fn write_report<F>(filename: &str, write: F) -> Result<()>
+where
+    F: FnOnce(&mut io::OutputWriter) -> std::io::Result<()>,
+{
+    let err = |e| Error::io(filename, e);
+    let mut writer = io::OutputWriter::create(filename, io::OutputCompression::None).map_err(err)?;
+    write(&mut writer).map_err(err)?;
+    writer.finish().map_err(err)
+}
+
+/// Hand on each scored record from a stream that returns each read once, in
+/// any order, tagged with every region it overlaps
+fn add_by_region<I>(records: I, ref_names: &[String], regions: &[HomopolymerRegion], contig_regions: &HashMap<&str, Range<usize>>, filter: &ReadFilter, bam: &str, add: &mut dyn FnMut((usize, bam::Record)) -> Result<()>) -> Result<()>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    for record in records {
+        let record = record.map_err(|e| Error::io(bam, e))?;
+        if !filter.accepts(&record) {
+            continue
+        }
+        let contig = ref_names[record.ref_id() as usize].as_str();
+        let start = record.start() as u32;
+        let end = record.calculate_end() as u32;
+        for region_idx in overlapping_regions(regions, contig_regions, contig, start, end) {
+            add((region_idx, record.clone()))?;
+        }
+    }
+    Ok(())
+}
+
+/// homopolymers in `region` covered by a read aligned from `start` to `end`
+fn region_homopolymers<'a>(homos: &'a HomopolymerIndex, region: &HomopolymerRegion, start: u32, end: u32) -> impl Iterator<Item = &'a HomopolymerRecord> {
+    homos.covered_by(&region.contig, std::cmp::max(start, region.start), std::cmp::min(end, region.stop))
+}
+
+/// the range of `regions` lying on each contig
+fn contig_regions(regions: &[HomopolymerRegion]) -> HashMap<&str, Range<usize>> {
+    let mut contig_regions: HashMap<&str, Range<usize>> = HashMap::new();
+    for (region_idx, region) in regions.iter().enumerate() {
+        contig_regions.entry(region.contig.as_str())
+            .and_modify(|range| range.end = region_idx + 1)
+            .or_insert(region_idx..region_idx + 1);
+    }
+    contig_regions
+}
+
+/// indices of the regions on `contig` that overlap `start..end`
+fn overlapping_regions(regions: &[HomopolymerRegion], contig_regions: &HashMap<&str, Range<usize>>, contig: &str, start: u32, end: u32) -> Range<usize> {
+    let range = match contig_regions.get(contig) {
+        Some(range) => range.clone(),
+        None => return 0..0,
+    };
+    // regions on a contig are sorted and do not overlap
+    let on_contig = &regions[range.clone()];
+    let first = on_contig.partition_point(|region| region.stop <= start);
+    let last = on_contig.partition_point(|region| region.start < end);
+    range.start + first..range.start + std::cmp::max(first, last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flank_must_be_at_least_1() {
+        let args = Opts::parse_from(["hope", "-a", "assembly.fasta", "-b", "reads.bam", "-i", "homopolymers.txt", "-o", "out_", "--flank", "0"]);
+        match score(args) {
+            Err(Error::Input(message)) => assert_eq!(message, "--flank must be at least 1"),
+            other => panic!("expected an input error, got {:?}", other),
+        }
+    }
 }
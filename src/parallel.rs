@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Mutex};
+
+/// Run `work` over every item handed to `produce` on `threads` worker threads.
+/// Results reach `sink` in the order the items were produced, so the output
+/// does not depend on the number of threads. Stops at the first error from
+/// `produce` or `sink`
+pub fn ordered_map<T, R, E, P, W, S>(threads: usize, produce: P, work: W, mut sink: S) -> Result<(), E>
+where
+    T: Send,
+    R: Send,
+    P: FnOnce(&mut dyn FnMut(T) -> Result<(), E>) -> Result<(), E>,
+    W: Fn(T) -> R + Sync,
+    S: FnMut(R) -> Result<(), E>,
+{
+    if threads <= 1 {
+        return produce(&mut |item| sink(work(item)))
+    }
+
+    // bounded so the producer can't run far ahead of the workers
+    let (item_tx, item_rx) = mpsc::sync_channel::<(usize, T)>(threads * 2);
+    let item_rx = Mutex::new(item_rx);
+    let (result_tx, result_rx) = mpsc::channel::<(usize, R)>();
+    let work = &work;
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            let item_rx = &item_rx;
+            let result_tx = result_tx.clone();
+            scope.spawn(move || loop {
+                // only hold the lock while waiting for the next item
+                let next = item_rx.lock().unwrap().recv();
+                match next {
+                    Ok((idx, item)) => {
+                        if result_tx.send((idx, work(item))).is_err() {
+                            break
+                        }
+                    }
+                    Err(_) => break,
+                }
+            });
+        }
+        drop(result_tx);
+
+        let mut pending: BTreeMap<usize, R> = BTreeMap::new();
+        let mut next_idx: usize = 0;
+        let mut emit = |pending: &mut BTreeMap<usize, R>| -> Result<(), E> {
+            while let Some(result) = pending.remove(&next_idx) {
+                sink(result)?;
+                next_idx += 1;
+            }
+            Ok(())
+        };
+
+        let mut item_idx: usize = 0;
+        let produced = produce(&mut |item| {
+            item_tx.send((item_idx, item)).expect("worker thread stopped");
+            item_idx += 1;
+            pending.extend(result_rx.try_iter());
+            emit(&mut pending)
+        });
+        // lets the workers finish once the queue is empty
+        drop(item_tx);
+        produced?;
+
+        for (idx, result) in result_rx.iter() {
+            pending.insert(idx, result);
+            emit(&mut pending)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// a sleep of up to 2ms that varies from item to item, so later items
+    /// often finish first
+    fn random_delay(item: usize) -> Duration {
+        let mut x = (item as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        x ^= x >> 33;
+        x = x.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+        x ^= x >> 33;
+        Duration::from_micros(x % 2000)
+    }
+
+    #[test]
+    fn results_are_in_input_order() {
+        for threads in [1, 2, 4, 8] {
+            let mut received = Vec::new();
+            let result: Result<(), String> = ordered_map(threads, |submit| {
+                for item in 0..200 {
+                    submit(item)?;
+                }
+                Ok(())
+            }, |item: usize| {
+                std::thread::sleep(random_delay(item));
+                item * 2
+            }, |result| {
+                received.push(result);
+                Ok(())
+            });
+            assert!(result.is_ok());
+            assert_eq!(received, (0..200).map(|item| item * 2).collect::<Vec<_>>(), "threads: {}", threads);
+        }
+    }
+
+    #[test]
+    fn work_error_stops_the_run() {
+        const FAIL: usize = 50;
+        const ITEMS: usize = 1000;
+        for threads in [1, 4] {
+            let mut submitted = 0;
+            let mut received = Vec::new();
+            let result = ordered_map(threads, |submit| {
+                for item in 0..ITEMS {
+                    submit(item)?;
+                    submitted += 1;
+                }
+                Ok(())
+            }, |item: usize| {
+                std::thread::sleep(random_delay(item));
+                if item == FAIL {
+                    Err(format!("item {} failed", item))
+                } else {
+                    Ok(item)
+                }
+            }, |result| {
+                received.push(result?);
+                Ok(())
+            });
+            assert_eq!(result, Err(format!("item {} failed", FAIL)));
+            // everything before the error reaches the sink, nothing after it
+            assert_eq!(received, (0..FAIL).collect::<Vec<_>>(), "threads: {}", threads);
+            assert!(submitted < ITEMS, "threads: {}, submitted: {}", threads, submitted);
+        }
+    }
+}
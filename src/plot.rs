@@ -0,0 +1,147 @@
+use std::fmt::Write;
+
+use crate::confusion::ConfusionMatrix;
+use crate::error::{Error, Result};
+use crate::summary::SiteSummary;
+
+/// Colour of each base, as in plot_hope.py
+pub const BASE_COLOURS: [(&str, &str); 4] = [
+    ("A", "#CC79A7"),
+    ("T", "#E69F00"),
+    ("C", "#009E73"),
+    ("G", "#0072B2"),
+];
+
+/// colour for bases not in `BASE_COLOURS`
+const OTHER_COLOUR: &str = "#999999";
+
+/// smallest fraction of reads shaded in the heatmap
+const HEATMAP_MIN_FRACTION: f64 = 1e-4;
+
+pub fn base_colour(base: &str) -> &'static str {
+    BASE_COLOURS.iter()
+        .find(|(b, _)| b.eq_ignore_ascii_case(base))
+        .map_or(OTHER_COLOUR, |(_, colour)| colour)
+}
+
+/// Write a histogram for every length and base with scored reads and a heatmap
+/// of all of them to files starting with `outprefix`. Returns the files written
+pub fn write_plots(matrix: &ConfusionMatrix, outprefix: &str) -> Result<Vec<String>> {
+    let mut written = Vec::new();
+    for (length, base, cell) in matrix.cells() {
+        if let Some(svg) = histogram_svg(length, base, cell) {
+            let filename = format!("{}hist_{}_{}.svg", outprefix, length, base);
+            std::fs::write(&filename, svg).map_err(|e| Error::io(&filename, e))?;
+            written.push(filename);
+        }
+    }
+    let filename = format!("{}heatmap.svg", outprefix);
+    std::fs::write(&filename, heatmap_svg(matrix)).map_err(|e| Error::io(&filename, e))?;
+    written.push(filename);
+    Ok(written)
+}
+
+/// The differences of the reads at homopolymers of one length and base, on a
+/// log scale. None if no read has a difference
+pub fn histogram_svg(length: u32, base: &str, cell: &SiteSummary) -> Option<String> {
+    let min = (*cell.differences.keys().next()?).min(0);
+    let max = (*cell.differences.keys().next_back()?).max(0);
+    let max_count = *cell.differences.values().max()?;
+
+    let (width, height) = (480.0, 320.0);
+    let (left, right, top, bottom) = (60.0, 20.0, 40.0, 50.0);
+    let (plot_width, plot_height) = (width - left - right, height - top - bottom);
+    let bins = (max - min + 1) as f64;
+    let bin_width = plot_width / bins;
+    // the axis runs from half a read to the next power of 10
+    let decades = (max_count as f64).log10().ceil().max(1.0);
+    let log_min = 0.5f64.log10();
+    let y = |count: f64| top + plot_height * (1.0 - (count.log10() - log_min) / (decades - log_min));
+
+    let mut svg = svg_start(width, height);
+    let _ = writeln!(svg, r#"<text x="{}" y="24" text-anchor="middle" font-size="14">length: {}, base: {}</text>"#, width / 2.0, length, escape(base));
+    let colour = base_colour(base);
+    for (&difference, &n) in &cell.differences {
+        let x = left + (difference - min) as f64 * bin_width;
+        let bar_top = y(n as f64);
+        let _ = writeln!(svg, r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}"><title>difference {}: {} reads</title></rect>"#,
+            x, bar_top, bin_width, top + plot_height - bar_top, colour, difference, n);
+    }
+
+    // axes
+    let _ = writeln!(svg, r#"<path d="M{left} {top} V{} H{}" fill="none" stroke="black"/>"#, top + plot_height, left + plot_width);
+    for decade in 0..=decades as i32 {
+        let tick = y(10f64.powi(decade));
+        let _ = writeln!(svg, r#"<line x1="{}" y1="{tick:.2}" x2="{left}" y2="{tick:.2}" stroke="black"/><text x="{}" y="{:.2}" text-anchor="end" font-size="11">{}</text>"#,
+            left - 4.0, left - 6.0, tick + 4.0, 10u64.pow(decade as u32));
+    }
+    let step = tick_step(max - min + 1);
+    for difference in (min..=max).filter(|difference| difference % step == 0) {
+        let x = left + ((difference - min) as f64 + 0.5) * bin_width;
+        let _ = writeln!(svg, r#"<line x1="{x:.2}" y1="{}" x2="{x:.2}" y2="{}" stroke="black"/><text x="{x:.2}" y="{}" text-anchor="middle" font-size="11">{}</text>"#,
+            top + plot_height, top + plot_height + 4.0, top + plot_height + 16.0, difference);
+    }
+    let _ = writeln!(svg, r#"<text x="{}" y="{}" text-anchor="middle" font-size="12">difference</text>"#, left + plot_width / 2.0, height - 10.0);
+    let _ = writeln!(svg, r#"<text x="14" y="{0}" text-anchor="middle" font-size="12" transform="rotate(-90 14 {0})">reads</text>"#, top + plot_height / 2.0);
+    svg.push_str("</svg>\n");
+    Some(svg)
+}
+
+/// One row per length and base and one column per difference, each square
+/// shaded by the fraction of the row's reads with that difference
+pub fn heatmap_svg(matrix: &ConfusionMatrix) -> String {
+    let rows: Vec<(u32, &str, &SiteSummary)> = matrix.cells().filter(|(_, _, cell)| !cell.differences.is_empty()).collect();
+    let min = rows.iter().filter_map(|(_, _, cell)| cell.differences.keys().next().copied()).min().unwrap_or(0).min(0);
+    let max = rows.iter().filter_map(|(_, _, cell)| cell.differences.keys().next_back().copied()).max().unwrap_or(0).max(0);
+    let columns = (max - min + 1) as f64;
+
+    let (left, right, top, bottom) = (70.0, 20.0, 40.0, 60.0);
+    let cell_width = (1200.0 / columns).clamp(4.0, 16.0);
+    let cell_height = 16.0;
+    let width = left + right + cell_width * columns;
+    let height = top + bottom + cell_height * rows.len() as f64;
+
+    let mut svg = svg_start(width, height);
+    let _ = writeln!(svg, r#"<text x="{}" y="24" text-anchor="middle" font-size="14">difference by homopolymer length and base</text>"#, width / 2.0);
+    for (row, (length, base, cell)) in rows.iter().enumerate() {
+        let y = top + row as f64 * cell_height;
+        let _ = writeln!(svg, r#"<text x="{}" y="{:.2}" text-anchor="end" font-size="11">{} {}</text>"#, left - 6.0, y + cell_height - 4.0, length, escape(base));
+        let total: u64 = cell.differences.values().sum();
+        let colour = base_colour(base);
+        for (&difference, &n) in &cell.differences {
+            let fraction = n as f64 / total as f64;
+            // shaded on a log scale so that rare differences still show
+            let opacity = ((fraction.log10() - HEATMAP_MIN_FRACTION.log10()) / -HEATMAP_MIN_FRACTION.log10()).clamp(0.05, 1.0);
+            let _ = writeln!(svg, r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{}" fill="{}" fill-opacity="{:.3}"><title>length {}, base {}, difference {}: {} reads ({:.4})</title></rect>"#,
+                left + (difference - min) as f64 * cell_width, y, cell_width, cell_height, colour, opacity, length, escape(base), difference, n, fraction);
+        }
+    }
+
+    let bottom_y = top + cell_height * rows.len() as f64;
+    let zero_x = left + (-min) as f64 * cell_width;
+    let _ = writeln!(svg, r##"<rect x="{left}" y="{top}" width="{:.2}" height="{:.2}" fill="none" stroke="#cccccc"/>"##, cell_width * columns, bottom_y - top);
+    let _ = writeln!(svg, r#"<rect x="{zero_x:.2}" y="{top}" width="{cell_width:.2}" height="{:.2}" fill="none" stroke="black"/>"#, bottom_y - top);
+    let step = tick_step(max - min + 1);
+    for difference in (min..=max).filter(|difference| difference % step == 0) {
+        let x = left + ((difference - min) as f64 + 0.5) * cell_width;
+        let _ = writeln!(svg, r#"<text x="{x:.2}" y="{:.2}" text-anchor="middle" font-size="11">{}</text>"#, bottom_y + 14.0, difference);
+    }
+    let _ = writeln!(svg, r#"<text x="{:.2}" y="{:.2}" text-anchor="middle" font-size="12">difference (shade: fraction of reads, log scale from {}% to 100%)</text>"#,
+        left + cell_width * columns / 2.0, bottom_y + 34.0, HEATMAP_MIN_FRACTION * 100.0);
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn svg_start(width: f64, height: f64) -> String {
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\" font-family=\"sans-serif\">\n<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n", width, height)
+}
+
+/// label every difference divisible by the step, for at most about 20 labels
+fn tick_step(bins: i32) -> i32 {
+    [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000].into_iter().find(|step| bins / step <= 20).unwrap_or(1000)
+}
+
+/// escape text for use in SVG or HTML
+pub(crate) fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
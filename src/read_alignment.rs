@@ -1,156 +1,802 @@
+use crate::error::{Error, Result};
 
+/// A single cigar operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CigarOp {
+    /// M
+    Match,
+    /// I
+    Insertion,
+    /// D
+    Deletion,
+    /// N, e.g. an intron in a spliced alignment
+    RefSkip,
+    /// S
+    SoftClip,
+    /// H
+    HardClip,
+    /// P
+    Padding,
+    /// =
+    SeqMatch,
+    /// X
+    SeqMismatch,
+}
+
+impl CigarOp {
+    /// the operation for a character of a SAM cigar string
+    pub fn from_char(c: char) -> Option<CigarOp> {
+        match c {
+            'M' => Some(CigarOp::Match),
+            'I' => Some(CigarOp::Insertion),
+            'D' => Some(CigarOp::Deletion),
+            'N' => Some(CigarOp::RefSkip),
+            'S' => Some(CigarOp::SoftClip),
+            'H' => Some(CigarOp::HardClip),
+            'P' => Some(CigarOp::Padding),
+            '=' => Some(CigarOp::SeqMatch),
+            'X' => Some(CigarOp::SeqMismatch),
+            _ => None,
+        }
+    }
+
+    /// does the operation step along the read sequence?
+    pub fn consumes_read(self) -> bool {
+        matches!(self, CigarOp::Match | CigarOp::Insertion | CigarOp::SoftClip | CigarOp::SeqMatch | CigarOp::SeqMismatch)
+    }
+}
+
+impl From<bam::record::cigar::Operation> for CigarOp {
+    fn from(op: bam::record::cigar::Operation) -> CigarOp {
+        use bam::record::cigar::Operation;
+        match op {
+            Operation::AlnMatch => CigarOp::Match,
+            Operation::Insertion => CigarOp::Insertion,
+            Operation::Deletion => CigarOp::Deletion,
+            Operation::Skip => CigarOp::RefSkip,
+            Operation::Soft => CigarOp::SoftClip,
+            Operation::Hard => CigarOp::HardClip,
+            Operation::Padding => CigarOp::Padding,
+            Operation::SeqMatch => CigarOp::SeqMatch,
+            Operation::SeqMismatch => CigarOp::SeqMismatch,
+        }
+    }
+}
+
+/// A read aligned to one contig of the assembly
 #[derive(Debug)]
 pub struct ReadAlignment {
-    pub cig: Vec<(String, u32)>,
+    pub cig: Vec<(CigarOp, u32)>,
     pub contig: String,
+    /// reference id in the bam header
     pub contig_id: i32,
+    /// 0-based start of the alignment on the reference
     pub pos: i32,
+    /// 0-based, exclusive end of the alignment on the reference
     pub end: i32,
     pub aligned_end: i32,
     pub name: String,
     pub seq: String,
     pub flag: u16,
+    /// ID of the read group from the RG tag, if the record has one
+    pub read_group: Option<String>,
+    pub mapq: u8,
+    /// edit distance from the NM tag, if the record has one
+    pub nm: Option<u32>,
+    /// the read's other alignments, from the SA tag
+    pub other_alignments: Vec<OtherAlignment>,
+}
+
+/// Another alignment of the same read, as listed in the SA tag
+#[derive(Debug, Clone)]
+pub struct OtherAlignment {
+    pub contig: String,
+    /// 0-based start of the alignment on the reference
+    pub pos: u32,
+    pub reverse: bool,
+    pub cig: Vec<(CigarOp, u32)>,
+    pub mapq: u8,
+}
+
+impl OtherAlignment {
+    /// Parse the SA tag: `contig,pos,strand,cigar,mapq,nm;` for each alignment,
+    /// with 1-based positions
+    pub fn parse_sa_tag(tag: &str) -> Option<Vec<OtherAlignment>> {
+        let mut others: Vec<OtherAlignment> = Vec::new();
+        for entry in tag.split(';').filter(|entry| !entry.is_empty()) {
+            let bits: Vec<&str> = entry.split(',').collect();
+            if bits.len() < 6 {
+                return None
+            }
+            others.push(OtherAlignment {
+                contig: bits[0].to_string(),
+                pos: bits[1].parse::<u32>().ok()?.checked_sub(1)?,
+                reverse: match bits[2] {
+                    "+" => false,
+                    "-" => true,
+                    _ => return None,
+                },
+                cig: parse_cigar(bits[3])?,
+                mapq: bits[4].parse::<u8>().ok()?,
+            });
+        }
+        Some(others)
+    }
+
+    /// see `ReadAlignment::query_span`
+    pub fn query_span(&self) -> (u32, u32) {
+        query_span(&self.cig, self.reverse)
+    }
+}
+
+fn parse_cigar(cigar: &str) -> Option<Vec<(CigarOp, u32)>> {
+    let mut cig: Vec<(CigarOp, u32)> = Vec::new();
+    let mut length: u32 = 0;
+    for c in cigar.chars() {
+        match c.to_digit(10) {
+            Some(digit) => length = length.checked_mul(10)?.checked_add(digit)?,
+            None => {
+                cig.push((CigarOp::from_char(c)?, length));
+                length = 0;
+            }
+        }
+    }
+    Some(cig)
+}
+
+/// Start and end of the aligned part of the read, counting clipped bases, in
+/// the orientation the read was sequenced in
+fn query_span(cig: &[(CigarOp, u32)], reverse: bool) -> (u32, u32) {
+    let is_clip = |c: &CigarOp| matches!(c, CigarOp::SoftClip | CigarOp::HardClip);
+    let leading: u32 = cig.iter().take_while(|(c, _)| is_clip(c)).map(|&(_, l)| l).sum();
+    let trailing: u32 = cig.iter().rev().take_while(|(c, _)| is_clip(c)).map(|&(_, l)| l).sum();
+    let total: u32 = cig.iter().filter(|(c, _)| c.consumes_read() || *c == CigarOp::HardClip).map(|&(_, l)| l).sum();
+    if reverse {
+        (trailing, total - leading)
+    } else {
+        (leading, total - trailing)
+    }
+}
+
+/// The read sequence must have a base for every cigar operation that steps
+/// along the read
+fn check_sequence_length(name: &str, cig: &[(CigarOp, u32)], seq: &str) -> Result<()> {
+    let query_length: u64 = cig.iter().filter(|(c, _)| c.consumes_read()).map(|&(_, l)| l as u64).sum();
+    if seq.len() as u64 != query_length {
+        return Err(Error::Input(format!("read {}: sequence has {} bases but the cigar needs {}", name, seq.len(), query_length)))
+    }
+    Ok(())
 }
 
 impl ReadAlignment {
+    /// Build from a bam record aligned to `contig`
+    pub fn from_record(record: &bam::Record, contig: &str) -> Result<ReadAlignment> {
+        // extract read name
+        let name: &str = std::str::from_utf8(record.name())
+            .map_err(|_| Error::Input(format!("read name {} is not valid UTF-8", String::from_utf8_lossy(record.name()))))?;
+
+        // extract cigar as vector of tuples
+        let mut cig: Vec<(CigarOp, u32)> = Vec::with_capacity(record.cigar().len());
+        for (l, c) in record.cigar().iter() {
+            cig.push((CigarOp::from(c), l));
+        }
+
+        let seq = String::from_utf8(record.sequence().to_vec())
+            .map_err(|_| Error::Input(format!("read {}: sequence is not valid UTF-8", name)))?;
+        // e.g. a SAM record with SEQ '*'
+        check_sequence_length(name, &cig, &seq)?;
+
+        let read_group = match record.tags().get(b"RG") {
+            Some(bam::record::tags::TagValue::String(rg, _)) => Some(String::from_utf8(rg.to_vec())
+                .map_err(|_| Error::Input(format!("read {}: read group is not valid UTF-8", name)))?),
+            _ => None,
+        };
+
+        let nm = match record.tags().get(b"NM") {
+            Some(bam::record::tags::TagValue::Int(nm, _)) if nm >= 0 => Some(nm as u32),
+            _ => None,
+        };
+
+        let other_alignments = match record.tags().get(b"SA") {
+            Some(bam::record::tags::TagValue::String(sa, _)) => std::str::from_utf8(sa).ok()
+                .and_then(OtherAlignment::parse_sa_tag)
+                .ok_or_else(|| Error::Input(format!("read {}: SA tag '{}' is not valid", name, String::from_utf8_lossy(sa))))?,
+            _ => Vec::new(),
+        };
+
+        let mut ra = ReadAlignment {
+            cig,
+            contig: contig.to_string(),
+            contig_id: record.ref_id(),
+            seq,
+            pos: record.start(),
+            end: record.calculate_end(),
+            aligned_end: 0,
+            name: name.to_string(),
+            flag: record.flag().0,
+            read_group,
+            mapq: record.mapq(),
+            nm,
+            other_alignments,
+        };
+        ra.aligned_end = ra.get_aligned_index(ra.end as u32) as i32;
+        Ok(ra)
+    }
+
+    pub fn is_supplementary(&self) -> bool {
+        self.flag & 0x800 != 0
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.flag & 0x10 != 0
+    }
+
+    /// Start and end of the aligned part of the read, counting clipped bases, in
+    /// the orientation the read was sequenced in
+    pub fn query_span(&self) -> (u32, u32) {
+        query_span(&self.cig, self.is_reverse())
+    }
+
+    /// Start and end of the read bases aligned between the reference positions
+    /// `start` and `stop`, in the orientation the read was sequenced in. If no
+    /// bases are aligned there the span is empty, at the read position where
+    /// they would be
+    pub fn query_range(&self, start: u32, stop: u32) -> (u32, u32) {
+        let mut query_idx: u32 = 0;
+        let mut ref_idx: u32 = self.pos as u32;
+        let mut range: Option<(u32, u32)> = None;
+        let mut empty_at: Option<u32> = None;
+        for &(c, l) in &self.cig {
+            match c {
+                CigarOp::SoftClip | CigarOp::HardClip => query_idx += l,
+                CigarOp::Padding => continue,
+                CigarOp::Insertion => {
+                    if ref_idx >= start && ref_idx < stop {
+                        let (from, _) = range.unwrap_or((query_idx, query_idx));
+                        range = Some((from, query_idx + l));
+                    }
+                    query_idx += l;
+                }
+                CigarOp::Deletion | CigarOp::RefSkip => {
+                    if ref_idx + l > start && ref_idx < stop {
+                        empty_at.get_or_insert(query_idx);
+                    }
+                    ref_idx += l;
+                }
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch => {
+                    let from = std::cmp::max(ref_idx, start);
+                    let to = std::cmp::min(ref_idx + l, stop);
+                    if from < to {
+                        let q_from = query_idx + from - ref_idx;
+                        let q_to = query_idx + to - ref_idx;
+                        range = Some((range.map_or(q_from, |(f, _)| f), q_to));
+                    }
+                    query_idx += l;
+                    ref_idx += l;
+                }
+            }
+        }
+        let (from, to) = range.unwrap_or_else(|| {
+            let at = empty_at.unwrap_or(query_idx);
+            (at, at)
+        });
+        if self.is_reverse() {
+            let total = query_idx;
+            (total - to, total - from)
+        } else {
+            (from, to)
+        }
+    }
+
+    /// Should a site between the reference positions `start` and `stop` be
+    /// counted from this alignment? Always true for primary alignments. A
+    /// supplementary alignment gives way if the read bases there are also
+    /// aligned by the primary alignment, or by another supplementary alignment
+    /// starting earlier on the reference, so each read position is only
+    /// counted once
+    pub fn counts_site(&self, start: u32, stop: u32) -> bool {
+        if !self.is_supplementary() {
+            return true
+        }
+        let (from, to) = self.query_range(start, stop);
+        let overlaps = |(span_from, span_to): (u32, u32)| {
+            if from == to {
+                from >= span_from && from < span_to
+            } else {
+                from < span_to && to > span_from
+            }
+        };
+        // the first SA entry of a supplementary alignment is the primary
+        let mut others = self.other_alignments.iter();
+        if let Some(primary) = others.next() {
+            if overlaps(primary.query_span()) {
+                return false
+            }
+        }
+        let this = (self.contig.as_str(), self.pos as u32);
+        !others.any(|other| (other.contig.as_str(), other.pos) < this && overlaps(other.query_span()))
+    }
+
+    /// read bases aligned to the reference, counting insertions but not clips
+    pub fn aligned_length(&self) -> u32 {
+        self.cig.iter()
+            .filter(|(c, _)| c.consumes_read() && *c != CigarOp::SoftClip)
+            .map(|&(_, l)| l)
+            .sum()
+    }
+
+    /// soft clipped bases as a fraction of the read sequence
+    pub fn soft_clip_fraction(&self) -> f64 {
+        if self.seq.is_empty() {
+            return 0.0
+        }
+        let clipped: u32 = self.cig.iter().filter(|(c, _)| *c == CigarOp::SoftClip).map(|&(_, l)| l).sum();
+        clipped as f64 / self.seq.len() as f64
+    }
+
+    /// Gap-compressed identity, where each insertion or deletion counts as one
+    /// difference whatever its length. Mismatches come from the NM tag if the
+    /// record has one, otherwise from comparing the read with `window`, which
+    /// holds the reference from `window_start`
+    pub fn gap_compressed_identity(&self, window: &[u8], window_start: u32) -> f64 {
+        let mut columns: u32 = 0;
+        let mut gap_opens: u32 = 0;
+        let mut gap_bases: u32 = 0;
+        let mut mismatches: u32 = 0;
+        let mut read_idx: usize = 0;
+        let mut ref_idx: usize = (self.pos as u32 - window_start) as usize;
+        let seq = self.seq.as_bytes();
+        for &(c, l) in &self.cig {
+            match c {
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch => {
+                    columns += l;
+                    if self.nm.is_none() {
+                        if c == CigarOp::SeqMismatch {
+                            mismatches += l;
+                        } else if c == CigarOp::Match {
+                            let read_bases = &seq[read_idx..read_idx + l as usize];
+                            let ref_bases = &window[ref_idx..ref_idx + l as usize];
+                            mismatches += read_bases.iter().zip(ref_bases).filter(|(a, b)| !a.eq_ignore_ascii_case(b)).count() as u32;
+                        }
+                    }
+                    read_idx += l as usize;
+                    ref_idx += l as usize;
+                }
+                CigarOp::Insertion => {
+                    gap_opens += 1;
+                    gap_bases += l;
+                    read_idx += l as usize;
+                }
+                CigarOp::Deletion => {
+                    gap_opens += 1;
+                    gap_bases += l;
+                    ref_idx += l as usize;
+                }
+                CigarOp::RefSkip => ref_idx += l as usize,
+                CigarOp::SoftClip => read_idx += l as usize,
+                CigarOp::HardClip | CigarOp::Padding => continue,
+            }
+        }
+        if let Some(nm) = self.nm {
+            mismatches = nm.saturating_sub(gap_bases);
+        }
+        if columns + gap_opens == 0 {
+            return 0.0
+        }
+        1.0 - (mismatches + gap_opens) as f64 / (columns + gap_opens) as f64
+    }
+
+    /// Reference position used for `pos` when slicing the alignment. Positions
+    /// inside a deletion, or just after one, are moved to its start
     pub fn get_aligned_index(&self, pos: u32) -> u32 {
         let mut ref_idx: u32 = self.pos.try_into().unwrap();
-        for (c, l) in &self.cig {
-            if vec!["H","S"].iter().any(|&i| i==c) {
-                continue
-            } else if c=="D" {
-                if ref_idx + l >= pos {
-                    break
-                } else {
-                	ref_idx += l;
+        for &(c, l) in &self.cig {
+            match c {
+                CigarOp::Deletion | CigarOp::RefSkip => {
+                    if ref_idx + l >= pos {
+                        break
+                    }
+                    ref_idx += l;
                 }
-            } else if c=="M" {
-            	if ref_idx + l >= pos {
-            		ref_idx += pos - ref_idx;
-                	break
-            	} else {
-                	ref_idx += l;
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch => {
+                    if ref_idx + l >= pos {
+                        ref_idx = pos;
+                        break
+                    }
+                    ref_idx += l;
                 }
-            } else if c=="I" {
-            	if ref_idx==pos {
-            		break
-            	}
-            } else {
-                println!("unrecognized cigar");
+                CigarOp::Insertion => {
+                    if ref_idx == pos {
+                        break
+                    }
+                }
+                CigarOp::SoftClip | CigarOp::HardClip | CigarOp::Padding => continue,
             }
         }
         ref_idx
     }
 
+    /// Extract the aligned read and reference sequences between the reference
+    /// positions `start` and `stop`. Gaps are shown as '-' and reference skipped
+    /// by the read (N, e.g. introns) as '>' in the read. Insertions lying at
+    /// `start` are included, those lying at `stop` are not
+    pub fn extract_alignment(&self, start: u32, stop: u32, ref_seq: &str) -> (String, String) {
+        let mut read_seq = String::new();
+        let mut aln_ref_seq = String::new();
+        self.extract_alignment_into(start, stop, ref_seq, &mut read_seq, &mut aln_ref_seq);
+        (read_seq, aln_ref_seq)
+    }
 
-    pub fn extract_alignment(&self, start: u32, stop: u32, ref_seq: &String) -> (String, String) {
+    /// As `extract_alignment`, but appends to existing strings so that buffers
+    /// can be reused between calls
+    pub fn extract_alignment_into(&self, start: u32, stop: u32, ref_seq: &str, read_seq: &mut String, aln_ref_seq: &mut String) {
         let mut read_idx: u32 = 0;
-        let mut read_seq = String::new();
         let mut ref_idx: u32 = self.pos.try_into().unwrap();
-        let mut aln_ref_seq = String::new();
-        // keep track of whether we are finding start or stop
-        let mut on_start = true;
-        let mut on_stop = false;
-        let mut pos = start;
-        for (c, l) in &self.cig {
-            if vec!["H","S"].iter().any(|&i| i==c) {
-                read_idx += l;
-                continue
-            } else if c=="D" {
-                if ref_idx + l >= pos {
-                    if on_start {
-                        // Add first bit of sequence
-                        read_seq.push_str(&"-".repeat((ref_idx + l - pos).try_into().unwrap()));
-                        aln_ref_seq.push_str(&ref_seq[pos.try_into().unwrap()..(ref_idx + l).try_into().unwrap()]);
-                        // now looking for end
-                        on_start = false;
-                        on_stop = true;
-                        pos = stop;
-                        
-                        // is stop also in this stretch?
-                        if ref_idx + l >= pos {
-                            // Add last bit of sequence
-                            read_seq.push_str(&"-".repeat((pos - ref_idx).try_into().unwrap()));
-                            aln_ref_seq.push_str(&ref_seq[ref_idx.try_into().unwrap()..pos.try_into().unwrap()]);
-                            break
-                        } else {
-                            // adjust ref index
-                            ref_idx += l;
-                            continue
+        for &(c, l) in &self.cig {
+            if ref_idx >= stop {
+                break
+            }
+            match c {
+                CigarOp::SoftClip => read_idx += l,
+                // hard clipped bases are not in the read sequence, padding is in neither sequence
+                CigarOp::HardClip | CigarOp::Padding => continue,
+                CigarOp::Insertion => {
+                    if ref_idx >= start {
+                        read_seq.push_str(&self.seq[read_idx as usize..(read_idx + l) as usize]);
+                        push_repeated(aln_ref_seq, '-', l);
+                    }
+                    read_idx += l;
+                }
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch | CigarOp::Deletion | CigarOp::RefSkip => {
+                    // part of this operation that lies between start and stop
+                    let from = std::cmp::max(ref_idx, start);
+                    let to = std::cmp::min(ref_idx + l, stop);
+                    if from < to {
+                        aln_ref_seq.push_str(&ref_seq[from as usize..to as usize]);
+                        match c {
+                            CigarOp::Deletion => push_repeated(read_seq, '-', to - from),
+                            // skipped reference (intron) is marked with '>' so it is not mistaken for a deletion
+                            CigarOp::RefSkip => push_repeated(read_seq, '>', to - from),
+                            _ => {
+                                let offset = read_idx + from - ref_idx;
+                                read_seq.push_str(&self.seq[offset as usize..(offset + to - from) as usize]);
+                            }
                         }
-                    } else if on_stop {
-                        // Add last bit of sequence
-                        read_seq.push_str(&"-".repeat((pos - ref_idx).try_into().unwrap()));
-                        aln_ref_seq.push_str(&ref_seq[ref_idx.try_into().unwrap()..pos.try_into().unwrap()]);
-                        break
                     }
-                } else if on_stop {
-                    // Add sequence
-                    read_seq.push_str(&"-".repeat((*l).try_into().unwrap()));
-                    aln_ref_seq.push_str(&ref_seq[ref_idx.try_into().unwrap()..(ref_idx + l).try_into().unwrap()]);
-                    ref_idx += l;
-                } else {
                     ref_idx += l;
+                    if c.consumes_read() {
+                        read_idx += l;
+                    }
                 }
-            } else if c=="M" {
-                if ref_idx + l >= pos {
-                    if on_start {
-                        // Add first bit of sequence
-                        let intermediate_index = pos - ref_idx;
-                        read_seq.push_str(&self.seq[(read_idx + intermediate_index).try_into().unwrap()..(read_idx + l).try_into().unwrap()]);
-                        aln_ref_seq.push_str(&ref_seq[pos.try_into().unwrap()..(ref_idx + l).try_into().unwrap()]);
-                        // now looking for end
-                        on_start = false;
-                        on_stop = true;
-                        pos = stop;
-
-                        // is stop also in this stretch?
-                        if ref_idx + l >= pos {
-                            // Add last bit of sequence
-                            let remaining = pos - ref_idx;
-                            read_seq.push_str(&self.seq[read_idx.try_into().unwrap()..(read_idx + remaining).try_into().unwrap()]);
-                            aln_ref_seq.push_str(&ref_seq[ref_idx.try_into().unwrap()..pos.try_into().unwrap()]);
-                            break
+            }
+        }
+    }
+}
+
+fn push_repeated(s: &mut String, c: char, n: u32) {
+    for _ in 0..n {
+        s.push(c);
+    }
+}
+
+/// The gapped alignment of a read against the reference, built with a single
+/// walk of the cigar so that any number of reference spans can be sliced from
+/// it. Gives the same results as the `ReadAlignment` methods of the same name
+#[derive(Debug)]
+pub struct AlignmentProjection {
+    pos: u32,
+    end: u32,
+    read_aln: String,
+    ref_aln: String,
+    /// column in the gapped alignment where each reference position from `pos`
+    /// to `end` begins, counting insertions lying at that position
+    columns: Vec<u32>,
+    /// `get_aligned_index` for each reference position from `pos` to `end`
+    aligned_index: Vec<u32>,
+}
+
+impl ReadAlignment {
+    /// Walk the cigar once, building the gapped alignment against `ref_seq`
+    pub fn project(&self, ref_seq: &str) -> AlignmentProjection {
+        self.project_window(ref_seq, 0)
+    }
+
+    /// As `project`, but `window` holds only the reference from `window_start`,
+    /// and must cover the alignment from `pos` to `end`
+    pub fn project_window(&self, window: &str, window_start: u32) -> AlignmentProjection {
+        let pos: u32 = self.pos.try_into().unwrap();
+        let span = std::cmp::max(self.end - self.pos, 0) as usize;
+        let mut read_aln = String::with_capacity(span + self.seq.len() / 10);
+        let mut ref_aln = String::with_capacity(read_aln.capacity());
+        let mut columns: Vec<u32> = Vec::with_capacity(span + 1);
+        let mut aligned_index: Vec<u32> = Vec::with_capacity(span + 1);
+        aligned_index.push(pos);
+
+        let mut read_idx: usize = 0;
+        let mut ref_idx: u32 = pos;
+        // column of the first insertion lying at ref_idx, if there are any
+        let mut insertion_column: Option<u32> = None;
+        for &(c, l) in &self.cig {
+            match c {
+                CigarOp::SoftClip => read_idx += l as usize,
+                CigarOp::HardClip | CigarOp::Padding => continue,
+                CigarOp::Insertion => {
+                    insertion_column.get_or_insert(read_aln.len() as u32);
+                    read_aln.push_str(&self.seq[read_idx..read_idx + l as usize]);
+                    push_repeated(&mut ref_aln, '-', l);
+                    read_idx += l as usize;
+                }
+                CigarOp::Match | CigarOp::SeqMatch | CigarOp::SeqMismatch | CigarOp::Deletion | CigarOp::RefSkip => {
+                    let op_start = ref_idx;
+                    for _ in 0..l {
+                        columns.push(insertion_column.take().unwrap_or(read_aln.len() as u32));
+                        match c {
+                            CigarOp::Deletion => read_aln.push('-'),
+                            CigarOp::RefSkip => read_aln.push('>'),
+                            _ => {
+                                read_aln.push_str(&self.seq[read_idx..read_idx + 1]);
+                                read_idx += 1;
+                            }
+                        }
+                        let window_idx = (ref_idx - window_start) as usize;
+                        ref_aln.push_str(&window[window_idx..window_idx + 1]);
+                        ref_idx += 1;
+                        // positions within or just after a gap are moved to its start
+                        if c.consumes_read() {
+                            aligned_index.push(ref_idx);
                         } else {
-                            // adjust index
-                            read_idx += l;
-                            ref_idx += l;
-                            continue
+                            aligned_index.push(op_start);
                         }
-                    } else if on_stop {
-                        // Add last bit of sequence
-                        let remaining = pos - ref_idx;
-                        read_seq.push_str(&self.seq[read_idx.try_into().unwrap()..(read_idx + remaining).try_into().unwrap()]);
-                        aln_ref_seq.push_str(&ref_seq[ref_idx.try_into().unwrap()..pos.try_into().unwrap()]);
-                        break
                     }
-                } else if on_stop {
-                    // Add sequence
-                    read_seq.push_str(&self.seq[read_idx.try_into().unwrap()..(read_idx + l).try_into().unwrap()]);
-                    aln_ref_seq.push_str(&ref_seq[ref_idx.try_into().unwrap()..(ref_idx + l).try_into().unwrap()]);
                 }
-                read_idx += l;
-                ref_idx += l;
-            } else if c=="I" {
-                if ref_idx == pos {
-                    if on_stop {
-                        // Add last bit of sequence
-                        read_seq.push_str(&self.seq[read_idx.try_into().unwrap()..(read_idx + l).try_into().unwrap()]);
-                        aln_ref_seq.push_str(&"-".repeat((*l).try_into().unwrap()));
-                        break
-                    }
-                } else if on_stop {
-                    // Add sequence 
-                    read_seq.push_str(&self.seq[read_idx.try_into().unwrap()..(read_idx + l).try_into().unwrap()]);
-                    aln_ref_seq.push_str(&"-".repeat((*l).try_into().unwrap()));
-                    read_idx += l;
-                } else {
-                    read_idx += l;
+            }
+        }
+        // insertions at the end of the alignment are never part of a span
+        columns.push(insertion_column.unwrap_or(read_aln.len() as u32));
+
+        AlignmentProjection {
+            pos,
+            end: ref_idx,
+            read_aln,
+            ref_aln,
+            columns,
+            aligned_index,
+        }
+    }
+}
+
+impl AlignmentProjection {
+    /// see `ReadAlignment::get_aligned_index`
+    pub fn get_aligned_index(&self, pos: u32) -> u32 {
+        let pos = pos.clamp(self.pos, self.end);
+        self.aligned_index[(pos - self.pos) as usize]
+    }
+
+    /// see `ReadAlignment::extract_alignment`
+    pub fn extract_alignment(&self, start: u32, stop: u32) -> (String, String) {
+        let start = start.clamp(self.pos, self.end);
+        let stop = stop.clamp(self.pos, self.end);
+        if start >= stop {
+            return (String::new(), String::new())
+        }
+        let from = self.columns[(start - self.pos) as usize] as usize;
+        let to = self.columns[(stop - self.pos) as usize] as usize;
+        (self.read_aln[from..to].to_string(), self.ref_aln[from..to].to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const REF: &str = "ACGTTGCAAGGCTTACCGATGCATCGGATC";
+
+    /// an alignment to `REF` starting at `pos`, with its end worked out from the cigar
+    pub(crate) fn alignment(pos: u32, cigar: &str, seq: &str) -> ReadAlignment {
+        let cig = parse_cigar(cigar).unwrap();
+        let ref_length: u32 = cig.iter()
+            .filter(|(c, _)| matches!(c, CigarOp::Match | CigarOp::Deletion | CigarOp::RefSkip | CigarOp::SeqMatch | CigarOp::SeqMismatch))
+            .map(|&(_, l)| l)
+            .sum();
+        let mut ra = ReadAlignment {
+            cig,
+            contig: "c1".to_string(),
+            contig_id: 0,
+            pos: pos as i32,
+            end: (pos + ref_length) as i32,
+            aligned_end: 0,
+            name: "r1".to_string(),
+            seq: seq.to_string(),
+            flag: 0,
+            read_group: None,
+            mapq: 60,
+            nm: None,
+            other_alignments: Vec::new(),
+        };
+        ra.aligned_end = ra.get_aligned_index(ra.end as u32) as i32;
+        ra
+    }
+
+    #[test]
+    fn sequence_length_must_match_cigar() {
+        // SEQ '*' gives an empty sequence
+        match check_sequence_length("r1", &parse_cigar("700M").unwrap(), "") {
+            Err(Error::Input(message)) => assert_eq!(message, "read r1: sequence has 0 bases but the cigar needs 700"),
+            other => panic!("expected an input error, got {:?}", other),
+        }
+        assert!(check_sequence_length("r1", &parse_cigar("2H3S4M1I2D").unwrap(), "ACGTACGT").is_ok());
+        assert!(check_sequence_length("r1", &parse_cigar("3S4M").unwrap(), "ACGTACGT").is_err());
+    }
+
+    fn identity(ra: &ReadAlignment) -> f64 {
+        ra.gap_compressed_identity(REF.as_bytes(), 0)
+    }
+
+    #[test]
+    fn gap_compressed_identity() {
+        assert_eq!(identity(&alignment(0, "10M", "ACGTTGCAAG")), 1.0);
+        // one mismatch in ten columns
+        assert_eq!(identity(&alignment(0, "10M", "ACGTAGCAAG")), 0.9);
+        assert_eq!(identity(&alignment(0, "4=1X5=", "ACGTAGCAAG")), 0.9);
+        // a gap counts once whatever its length
+        assert_eq!(identity(&alignment(0, "4M2I6M", "ACGTAATGCAAG")), 1.0 - 1.0 / 11.0);
+        assert_eq!(identity(&alignment(0, "4M3D6M", "ACGTAAGGCT")), 1.0 - 1.0 / 11.0);
+        assert_eq!(identity(&alignment(0, "4M1D2M1D6M", "ACGTGCAGGCTT")), 1.0 - 2.0 / 14.0);
+        // clipped bases are left out
+        assert_eq!(identity(&alignment(0, "3S8M2H", "GGGACGTTGCA")), 1.0);
+        // NM counts the deleted bases as well as the mismatches
+        let mut ra = alignment(0, "4M3D6M", "ACGTAAGGCT");
+        ra.nm = Some(4);
+        assert_eq!(identity(&ra), 1.0 - 2.0 / 11.0);
+        // only the reference under the read is needed
+        let ra = alignment(7, "5M", "AAGGA");
+        assert_eq!(ra.gap_compressed_identity(&REF.as_bytes()[5..], 5), 0.8);
+    }
+
+    #[test]
+    fn aligned_length_and_soft_clip() {
+        let ra = alignment(0, "2S6M1I1D1S", "TTACGTTGACC");
+        assert_eq!(ra.aligned_length(), 7);
+        assert_eq!(ra.soft_clip_fraction(), 3.0 / 11.0);
+        assert_eq!(alignment(0, "5H10M", "ACGTTGCAAG").soft_clip_fraction(), 0.0);
+    }
+
+    fn extract(ra: &ReadAlignment, start: u32, stop: u32) -> (String, String) {
+        ra.extract_alignment(start, stop, REF)
+    }
+
+    fn strings(read: &str, reference: &str) -> (String, String) {
+        (read.to_string(), reference.to_string())
+    }
+
+    #[test]
+    fn extract_insertion() {
+        let ra = alignment(2, "5M2I3M", "GTTGCAAAAG");
+        assert_eq!(extract(&ra, 2, 10), strings("GTTGCAAAAG", "GTTGC--AAG"));
+        // insertions at the start of the span are included, those at the stop are not
+        assert_eq!(extract(&ra, 7, 10), strings("AAAAG", "--AAG"));
+        assert_eq!(extract(&ra, 2, 7), strings("GTTGC", "GTTGC"));
+    }
+
+    #[test]
+    fn extract_deletion() {
+        let ra = alignment(0, "3M1D4M", "ACGTGCA");
+        assert_eq!(extract(&ra, 0, 8), strings("ACG-TGCA", "ACGTTGCA"));
+        assert_eq!(extract(&ra, 3, 5), strings("-T", "TT"));
+    }
+
+    #[test]
+    fn extract_ref_skip() {
+        let ra = alignment(0, "2M3N2M", "ACGC");
+        assert_eq!(extract(&ra, 0, 7), strings("AC>>>GC", "ACGTTGC"));
+    }
+
+    #[test]
+    fn extract_soft_clip_and_sequence_match() {
+        let ra = alignment(3, "2S4=1X", "NNTTGCT");
+        assert_eq!(extract(&ra, 3, 8), strings("TTGCT", "TTGCA"));
+        assert_eq!(extract(&ra, 7, 8), strings("T", "A"));
+    }
+
+    #[test]
+    fn extract_padding() {
+        let ra = alignment(0, "2M1P2M", "ACGT");
+        assert_eq!(extract(&ra, 0, 4), strings("ACGT", "ACGT"));
+    }
+
+    #[test]
+    fn projection_matches_extract_alignment() {
+        let alignments = [
+            alignment(2, "5M2I3M", "GTTGCAAAAG"),
+            alignment(0, "3M1D4M", "ACGTGCA"),
+            alignment(0, "2M3N2M", "ACGC"),
+            alignment(3, "2S4=1X", "NNTTGCT"),
+            alignment(0, "2M1P2M", "ACGT"),
+            alignment(4, "2I4M", "GGTGCA"),
+            alignment(4, "4M2I", "TGCATT"),
+            alignment(1, "1S3M2D2I1M1I2D3N4M3S", "NCGTAACGTTACAAA"),
+            alignment(5, "2M1I1I2D2M", "GCTAGG"),
+        ];
+        for ra in &alignments {
+            let (pos, end) = (ra.pos as u32, ra.end as u32);
+            let proj = ra.project(REF);
+            // a window holding only the reference under the read gives the same projection
+            let window_proj = ra.project_window(&REF[pos as usize..end as usize], pos);
+            for start in pos..=end {
+                assert_eq!(proj.get_aligned_index(start), ra.get_aligned_index(start), "{:?} at {}", ra.cig, start);
+                for stop in start..=end {
+                    let expected = extract(ra, start, stop);
+                    assert_eq!(proj.extract_alignment(start, stop), expected, "{:?} from {} to {}", ra.cig, start, stop);
+                    assert_eq!(window_proj.extract_alignment(start, stop), expected, "{:?} from {} to {}", ra.cig, start, stop);
                 }
-            } else {
-                println!("unrecognized cigar");
             }
         }
-        (read_seq, aln_ref_seq)
+    }
+
+    #[test]
+    fn parse_sa_tag() {
+        let others = OtherAlignment::parse_sa_tag("c1,101,+,20M10S,60,0;c2,5,-,10S20M,30,1;").unwrap();
+        assert_eq!(others.len(), 2);
+        assert_eq!((others[0].contig.as_str(), others[0].pos, others[0].reverse, others[0].mapq), ("c1", 100, false, 60));
+        assert_eq!(others[0].cig, vec![(CigarOp::Match, 20), (CigarOp::SoftClip, 10)]);
+        assert_eq!((others[1].contig.as_str(), others[1].pos, others[1].reverse, others[1].mapq), ("c2", 4, true, 30));
+        // in the orientation the read was sequenced in
+        assert_eq!(others[1].query_span(), (0, 20));
+        assert_eq!(OtherAlignment::parse_sa_tag("c1,101,+,20M10S,60,0").unwrap().len(), 1);
+        assert!(OtherAlignment::parse_sa_tag("").unwrap().is_empty());
+
+        for malformed in [
+            "c1,101,+,20M10S,60",
+            "c1,0,+,20M10S,60,0",
+            "c1,x,+,20M10S,60,0",
+            "c1,101,*,20M10S,60,0",
+            "c1,101,+,20Q10S,60,0",
+            "c1,101,+,20M10S,600,0",
+            "c1,101,+,20M10S,60,0;c2,5,-,10S20M",
+        ] {
+            assert!(OtherAlignment::parse_sa_tag(malformed).is_none(), "{}", malformed);
+        }
+    }
+
+    /// a supplementary alignment with the given SA tag, of a 30 base read
+    fn supplementary(pos: u32, cigar: &str, sa: &str) -> ReadAlignment {
+        let mut ra = alignment(pos, cigar, &"A".repeat(30));
+        ra.flag = 0x800;
+        ra.other_alignments = OtherAlignment::parse_sa_tag(sa).unwrap();
+        ra
+    }
+
+    #[test]
+    fn supplementary_gives_way_to_primary() {
+        // the primary aligns read bases 0..20, this alignment read bases 15..30 from 500
+        let ra = supplementary(500, "15S15M", "c1,101,+,20M10S,60,0;");
+        assert!(!ra.counts_site(501, 504));
+        assert!(ra.counts_site(508, 512));
+
+        let mut primary = alignment(100, "20M10S", &"A".repeat(30));
+        primary.other_alignments = OtherAlignment::parse_sa_tag("c1,501,+,15S15M,60,0;").unwrap();
+        assert!(primary.counts_site(101, 104));
+    }
+
+    #[test]
+    fn overlapping_supplementaries_count_once() {
+        // primary aligns read bases 0..10, a aligns 8..30 from 300 and b 15..30 from 200
+        let a = supplementary(300, "8S22M", "c1,101,+,10M20S,60,0;c1,201,+,15S15M,60,0;");
+        let b = supplementary(200, "15S15M", "c1,101,+,10M20S,60,0;c1,301,+,8S22M,60,0;");
+        // read bases 20..25 are aligned by both, and b starts earlier
+        assert!(!a.counts_site(312, 317));
+        assert!(b.counts_site(205, 210));
+        // read bases 10..14 are only aligned by a
+        assert!(a.counts_site(302, 306));
+        // read bases 8..10 are also aligned by the primary
+        assert!(!a.counts_site(300, 302));
+    }
+
+    #[test]
+    fn extract_leading_and_trailing_insertions() {
+        let ra = alignment(4, "2I4M", "GGTGCA");
+        assert_eq!(extract(&ra, 4, 8), strings("GGTGCA", "--TGCA"));
+        let ra = alignment(4, "4M2I", "TGCATT");
+        assert_eq!(extract(&ra, 4, 8), strings("TGCA", "TGCA"));
     }
 }
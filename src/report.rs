@@ -0,0 +1,148 @@
+use std::fmt::Write as _;
+use std::io::Write;
+
+use crate::confusion::ConfusionMatrix;
+use crate::homopolymer::{HomopolymerIndex, HomopolymerRecord};
+use crate::plot::{self, escape};
+use crate::scoring::FilterCounts;
+use crate::summary::{format_option, SiteSummaries, SiteSummary};
+
+/// number of sites listed as the worst performing
+const WORST_SITES: usize = 20;
+/// sites with fewer reads that were not skipped are left out of the worst sites
+const WORST_SITE_MIN_READS: u64 = 5;
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin: 0.5em 0 1.5em; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: right; }
+th { background: #f0f0f0; }
+td.text, th.text { text-align: left; }
+.plots { display: flex; flex-wrap: wrap; gap: 0.5em; }
+.heatmap { overflow-x: auto; }";
+
+/// A single HTML file with no external assets, summarising a run. Samples
+/// are added as they are scored
+pub struct Report {
+    parameters: Vec<(String, String)>,
+    counts: Vec<(String, FilterCounts)>,
+    /// each sample's section of the report
+    sections: Vec<String>,
+}
+
+impl Report {
+    /// `parameters` are listed as given at the top of the report
+    pub fn new(parameters: Vec<(String, String)>) -> Report {
+        Report { parameters, counts: Vec::new(), sections: Vec::new() }
+    }
+
+    /// Summarise one sample's reads. `sites` and `matrix` should hold the
+    /// scores of the reads counted in `counts`
+    pub fn add_sample(&mut self, name: &str, counts: FilterCounts, sites: &SiteSummaries, homos: &HomopolymerIndex, matrix: &ConfusionMatrix) {
+        let mut section = String::new();
+        let _ = writeln!(section, "<h2>Sample: {}</h2>", escape(name));
+        section.push_str("<h3>Accuracy by length and base</h3>\n");
+        section.push_str(&accuracy_table(matrix));
+        section.push_str("<h3>Differences</h3>\n");
+        let _ = writeln!(section, "<div class=\"heatmap\">\n{}</div>\n<div class=\"plots\">", plot::heatmap_svg(matrix));
+        for (length, base, cell) in matrix.cells() {
+            if let Some(svg) = plot::histogram_svg(length, base, cell) {
+                section.push_str(&svg);
+            }
+        }
+        section.push_str("</div>\n");
+        let _ = writeln!(section, "<h3>Worst sites</h3>\n<p>The {} sites with the lowest fraction correct, of those with at least {} reads that were not skipped.</p>", WORST_SITES, WORST_SITE_MIN_READS);
+        section.push_str(&worst_sites_table(sites, homos));
+        section.push_str("<h3>Contigs</h3>\n");
+        section.push_str(&contig_table(sites, homos));
+        self.counts.push((name.to_string(), counts));
+        self.sections.push(section);
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut html = String::new();
+        let _ = writeln!(html, "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>hope report</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>hope report</h1>", STYLE);
+
+        html.push_str("<h2>Run parameters</h2>\n<table>\n");
+        for (name, value) in &self.parameters {
+            let _ = writeln!(html, "<tr><th class=\"text\">{}</th><td class=\"text\">{}</td></tr>", escape(name), escape(value));
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Read filters</h2>\n<p>Reads covering at least one homopolymer, by the first filter that removed them.</p>\n<table>\n");
+        html.push_str("<tr><th class=\"text\">sample</th><th>scored</th><th>mapq</th><th>aligned length</th><th>soft clip</th><th>identity</th></tr>\n");
+        for (name, counts) in &self.counts {
+            let _ = writeln!(html, "<tr><td class=\"text\">{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(name), counts.scored, counts.mapq, counts.aligned_length, counts.soft_clip, counts.identity);
+        }
+        html.push_str("</table>\n");
+
+        for section in &self.sections {
+            html.push_str(section);
+        }
+        html.push_str("</body>\n</html>\n");
+        writer.write_all(html.as_bytes())
+    }
+}
+
+const SUMMARY_HEADER: &str = "<th>depth</th><th>skip</th><th>unknown</th><th>mismatch</th><th>fraction correct</th><th>mean difference</th><th>modal difference</th>";
+
+/// the `SUMMARY_HEADER` columns for `summary`
+fn summary_cells(summary: &SiteSummary) -> String {
+    format!("<td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td>",
+        summary.depth(),
+        summary.skip,
+        summary.unknown,
+        summary.mismatch,
+        format_option(summary.fraction_correct().map(|f| format!("{:.4}", f))),
+        format_option(summary.mean_difference().map(|m| format!("{:.4}", m))),
+        format_option(summary.modal_difference().map(|m| m.to_string())),
+    )
+}
+
+fn accuracy_table(matrix: &ConfusionMatrix) -> String {
+    let mut table = format!("<table>\n<tr><th>length</th><th class=\"text\">base</th>{}</tr>\n", SUMMARY_HEADER);
+    for (length, base, cell) in matrix.cells() {
+        let _ = writeln!(table, "<tr><td>{}</td><td class=\"text\">{}</td>{}</tr>", length, escape(base), summary_cells(cell));
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+fn worst_sites_table(sites: &SiteSummaries, homos: &HomopolymerIndex) -> String {
+    let mut worst: Vec<(&HomopolymerRecord, SiteSummary, f64)> = homos.contigs().iter()
+        .flat_map(|contig| homos.homopolymers(contig))
+        .map(|homo| (homo, sites.combined(homo)))
+        .filter(|(_, site)| site.depth() - site.skip >= WORST_SITE_MIN_READS)
+        .filter_map(|(homo, site)| site.fraction_correct().map(|fraction| (homo, site, fraction)))
+        .collect();
+    // ties keep homopolymer order
+    worst.sort_by(|a, b| a.2.total_cmp(&b.2));
+    worst.truncate(WORST_SITES);
+
+    let mut table = format!("<table>\n<tr><th class=\"text\">contig</th><th>start</th><th>stop</th><th class=\"text\">base</th><th>length</th>{}</tr>\n", SUMMARY_HEADER);
+    for (homo, site, _) in &worst {
+        let _ = writeln!(table, "<tr><td class=\"text\">{}</td><td>{}</td><td>{}</td><td class=\"text\">{}</td><td>{}</td>{}</tr>",
+            escape(&homo.contig), homo.start + 1, homo.stop, escape(&homo.base), homo.length, summary_cells(site));
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+fn contig_table(sites: &SiteSummaries, homos: &HomopolymerIndex) -> String {
+    let mut table = format!("<table>\n<tr><th class=\"text\">contig</th><th>homopolymers</th><th>covered</th>{}</tr>\n", SUMMARY_HEADER);
+    for contig in homos.contigs() {
+        let mut summary = SiteSummary::default();
+        let mut covered = 0;
+        for homo in homos.homopolymers(contig) {
+            let site = sites.combined(homo);
+            if site.depth() > 0 {
+                covered += 1;
+            }
+            summary.merge(&site);
+        }
+        let _ = writeln!(table, "<tr><td class=\"text\">{}</td><td>{}</td><td>{}</td>{}</tr>",
+            escape(contig), homos.homopolymers(contig).len(), covered, summary_cells(&summary));
+    }
+    table.push_str("</table>\n");
+    table
+}
@@ -0,0 +1,303 @@
+use crate::error::{Error, Result};
+use crate::homopolymer::{FlankLengths, HomopolymerIndex, HomopolymerRecord, HomopolymerResult};
+use crate::io::Assembly;
+use crate::read_alignment::{AlignmentProjection, ReadAlignment};
+
+/// Thresholds a read must pass to be scored. The default passes every read
+#[derive(Debug, Clone)]
+pub struct ReadFilter {
+    pub min_mapq: u8,
+    /// see `ReadAlignment::aligned_length`
+    pub min_aligned_length: u32,
+    /// largest fraction of the read that may be soft clipped
+    pub max_soft_clip: f64,
+    /// see `ReadAlignment::gap_compressed_identity`
+    pub min_identity: f64,
+    /// score supplementary alignments as well as primary ones
+    pub supplementary: bool,
+    /// minimum mapping quality for supplementary alignments, on top of `min_mapq`
+    pub min_supplementary_mapq: u8,
+}
+
+impl Default for ReadFilter {
+    fn default() -> ReadFilter {
+        ReadFilter {
+            min_mapq: 0,
+            min_aligned_length: 0,
+            max_soft_clip: 1.0,
+            min_identity: 0.0,
+            supplementary: false,
+            min_supplementary_mapq: 0,
+        }
+    }
+}
+
+/// The filter that removed a read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    Mapq,
+    AlignedLength,
+    SoftClip,
+    Identity,
+}
+
+impl ReadFilter {
+    /// Should a record be considered at all? Unmapped and secondary alignments
+    /// are skipped, and supplementary alignments unless `supplementary` is set
+    pub fn accepts(&self, record: &bam::Record) -> bool {
+        record.flag().is_mapped() && !record.flag().is_secondary() && (self.supplementary || !record.flag().is_supplementary())
+    }
+
+    /// The first filter `ra` fails, if any. `window` holds the reference from
+    /// `window_start` and must cover the alignment
+    pub fn check(&self, ra: &ReadAlignment, window: &str, window_start: u32) -> Option<FilterReason> {
+        if ra.mapq < self.min_mapq || (ra.is_supplementary() && ra.mapq < self.min_supplementary_mapq) {
+            return Some(FilterReason::Mapq)
+        }
+        if ra.aligned_length() < self.min_aligned_length {
+            return Some(FilterReason::AlignedLength)
+        }
+        if ra.soft_clip_fraction() > self.max_soft_clip {
+            return Some(FilterReason::SoftClip)
+        }
+        // the identity is only worked out if it is needed
+        if self.min_identity > 0.0 && ra.gap_compressed_identity(window.as_bytes(), window_start) < self.min_identity {
+            return Some(FilterReason::Identity)
+        }
+        None
+    }
+}
+
+/// Number of reads scored and removed by each filter
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FilterCounts {
+    pub scored: u64,
+    pub mapq: u64,
+    pub aligned_length: u64,
+    pub soft_clip: u64,
+    pub identity: u64,
+}
+
+impl FilterCounts {
+    pub fn count(&mut self, reason: Option<FilterReason>) {
+        match reason {
+            None => self.scored += 1,
+            Some(FilterReason::Mapq) => self.mapq += 1,
+            Some(FilterReason::AlignedLength) => self.aligned_length += 1,
+            Some(FilterReason::SoftClip) => self.soft_clip += 1,
+            Some(FilterReason::Identity) => self.identity += 1,
+        }
+    }
+
+    pub fn add(&mut self, other: &FilterCounts) {
+        self.scored += other.scored;
+        self.mapq += other.mapq;
+        self.aligned_length += other.aligned_length;
+        self.soft_clip += other.soft_clip;
+        self.identity += other.identity;
+    }
+}
+
+impl std::fmt::Display for FilterCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} reads scored, removed by filters: mapq {}, aligned length {}, soft clip {}, identity {}",
+            self.scored, self.mapq, self.aligned_length, self.soft_clip, self.identity)
+    }
+}
+
+/// What `ScoredRead::from_record` made of a record
+#[derive(Debug)]
+pub enum ReadOutcome<'a> {
+    /// the read counts at none of the homopolymers
+    NotCovered,
+    /// the read counts at a homopolymer but was removed by a filter
+    Filtered(ReadAlignment, FilterReason),
+    Scored(ScoredRead<'a>),
+}
+
+/// A read together with the homopolymers covered by its alignment
+#[derive(Debug)]
+pub struct ScoredRead<'a> {
+    pub ra: ReadAlignment,
+    pub homos: Vec<&'a HomopolymerRecord>,
+    proj: AlignmentProjection,
+    flanks: FlankLengths,
+}
+
+impl<'a> ScoredRead<'a> {
+    /// `homos` should lie within the alignment of `ra` to `ref_seq`
+    pub fn new(ra: ReadAlignment, homos: Vec<&'a HomopolymerRecord>, ref_seq: &str) -> ScoredRead<'a> {
+        // walk the alignment once for all homopolymers in the read
+        let proj = ra.project(ref_seq);
+        ScoredRead { ra, homos, proj, flanks: FlankLengths::default() }
+    }
+
+    /// As `new`, but `window` holds only the reference from `window_start`, and
+    /// must cover the alignment of `ra`
+    pub fn with_window(ra: ReadAlignment, homos: Vec<&'a HomopolymerRecord>, window: &str, window_start: u32) -> ScoredRead<'a> {
+        let proj = ra.project_window(window, window_start);
+        ScoredRead { ra, homos, proj, flanks: FlankLengths::default() }
+    }
+
+    /// Score `record`, aligned to `contig`, at those of `homos` where it counts
+    /// (see `ReadAlignment::counts_site`). `homos` should be the homopolymers
+    /// the record overlaps, and `record` should pass `ReadFilter::accepts`.
+    /// Only the reference under the read is fetched from `assembly`
+    pub fn from_record(record: &bam::Record, contig: &str, mut homos: Vec<&'a HomopolymerRecord>, assembly: &Assembly, filter: &ReadFilter, flanks: FlankLengths) -> Result<ReadOutcome<'a>> {
+        if homos.is_empty() {
+            return Ok(ReadOutcome::NotCovered)
+        }
+        let ra = ReadAlignment::from_record(record, contig)?;
+        // sites already covered by the read's primary alignment are left to it
+        homos.retain(|homo| ra.counts_site(homo.start, homo.stop));
+        if homos.is_empty() {
+            return Ok(ReadOutcome::NotCovered)
+        }
+        let window_start = ra.pos as u32;
+        let window = assembly.fetch(contig, window_start, ra.end as u32)?;
+        if let Some(reason) = filter.check(&ra, &window, window_start) {
+            return Ok(ReadOutcome::Filtered(ra, reason))
+        }
+        Ok(ReadOutcome::Scored(ScoredRead::with_window(ra, homos, &window, window_start).with_flanks(flanks)))
+    }
+
+    /// Score with flanks other than the default 30 bases
+    pub fn with_flanks(mut self, flanks: FlankLengths) -> ScoredRead<'a> {
+        self.flanks = flanks;
+        self
+    }
+
+    /// Score each homopolymer, in order of homopolymer start
+    pub fn results(&self) -> impl Iterator<Item = HomopolymerResult<'_>> {
+        self.homos.iter().map(move |homo| HomopolymerResult::new(homo, &self.ra, &self.proj, self.flanks))
+    }
+}
+
+/// Iterator over the reads from a bam file that cover at least one homopolymer.
+/// Records may come in any order, so any `bam` reader can be used
+pub struct ScoredReads<'a, I> {
+    records: I,
+    ref_names: Vec<String>,
+    homos: &'a HomopolymerIndex,
+    assembly: &'a Assembly,
+    filter: ReadFilter,
+    flanks: FlankLengths,
+}
+
+impl<'a, I> ScoredReads<'a, I>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    /// `header` is the header of the bam file `records` are read from. Contigs
+    /// are looked up in `assembly` by name
+    pub fn new(records: I, header: &bam::Header, homos: &'a HomopolymerIndex, assembly: &'a Assembly) -> ScoredReads<'a, I> {
+        ScoredReads {
+            records,
+            ref_names: header.reference_names().to_vec(),
+            homos,
+            assembly,
+            filter: ReadFilter::default(),
+            flanks: FlankLengths::default(),
+        }
+    }
+
+    /// Skip reads that fail `filter`
+    pub fn with_filter(mut self, filter: ReadFilter) -> ScoredReads<'a, I> {
+        self.filter = filter;
+        self
+    }
+
+    /// see `ScoredRead::with_flanks`
+    pub fn with_flanks(mut self, flanks: FlankLengths) -> ScoredReads<'a, I> {
+        self.flanks = flanks;
+        self
+    }
+}
+
+impl<'a, I> Iterator for ScoredReads<'a, I>
+where
+    I: Iterator<Item = std::io::Result<bam::Record>>,
+{
+    type Item = Result<ScoredRead<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for record in self.records.by_ref() {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => return Some(Err(Error::Input(format!("unable to read bam record: {}", e)))),
+            };
+            if !self.filter.accepts(&record) {
+                continue
+            }
+            let contig = &self.ref_names[record.ref_id() as usize];
+            if self.assembly.contig_len(contig).is_none() {
+                continue
+            }
+            let homos: Vec<&HomopolymerRecord> = self.homos.covered_by(contig, record.start() as u32, record.calculate_end() as u32).collect();
+            match ScoredRead::from_record(&record, contig, homos, self.assembly, &self.filter, self.flanks) {
+                Ok(ReadOutcome::Scored(read)) => return Some(Ok(read)),
+                Ok(_) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_alignment::tests::{alignment, REF};
+
+    fn check(filter: &ReadFilter, ra: &ReadAlignment) -> Option<FilterReason> {
+        filter.check(ra, REF, 0)
+    }
+
+    #[test]
+    fn filter_thresholds() {
+        // mapq 60, 10 aligned bases, one mismatch, 2 of 12 bases soft clipped
+        let ra = alignment(0, "2S10M", "TTACGTAGCAAG");
+        assert_eq!(check(&ReadFilter::default(), &ra), None);
+
+        let filter = |f: fn(&mut ReadFilter)| {
+            let mut filter = ReadFilter::default();
+            f(&mut filter);
+            filter
+        };
+        assert_eq!(check(&filter(|f| f.min_mapq = 60), &ra), None);
+        assert_eq!(check(&filter(|f| f.min_mapq = 61), &ra), Some(FilterReason::Mapq));
+        assert_eq!(check(&filter(|f| f.min_aligned_length = 10), &ra), None);
+        assert_eq!(check(&filter(|f| f.min_aligned_length = 11), &ra), Some(FilterReason::AlignedLength));
+        assert_eq!(check(&filter(|f| f.max_soft_clip = 0.2), &ra), None);
+        assert_eq!(check(&filter(|f| f.max_soft_clip = 0.1), &ra), Some(FilterReason::SoftClip));
+        assert_eq!(check(&filter(|f| f.min_identity = 0.9), &ra), None);
+        assert_eq!(check(&filter(|f| f.min_identity = 0.95), &ra), Some(FilterReason::Identity));
+
+        // the supplementary threshold only applies to supplementary alignments
+        let supplementary_mapq = filter(|f| f.min_supplementary_mapq = 61);
+        assert_eq!(check(&supplementary_mapq, &ra), None);
+        let mut supplementary = alignment(0, "2S10M", "TTACGTAGCAAG");
+        supplementary.flag = 0x800;
+        assert_eq!(check(&supplementary_mapq, &supplementary), Some(FilterReason::Mapq));
+
+        // the first filter failed is the one reported
+        let strict = ReadFilter { min_mapq: 61, min_aligned_length: 11, max_soft_clip: 0.1, min_identity: 0.95, ..ReadFilter::default() };
+        assert_eq!(check(&strict, &ra), Some(FilterReason::Mapq));
+        assert_eq!(check(&ReadFilter { min_mapq: 0, ..strict.clone() }, &ra), Some(FilterReason::AlignedLength));
+        assert_eq!(check(&ReadFilter { min_mapq: 0, min_aligned_length: 0, ..strict.clone() }, &ra), Some(FilterReason::SoftClip));
+        assert_eq!(check(&ReadFilter { min_mapq: 0, min_aligned_length: 0, max_soft_clip: 1.0, ..strict }, &ra), Some(FilterReason::Identity));
+    }
+
+    #[test]
+    fn filter_counts() {
+        let mut counts = FilterCounts::default();
+        for reason in [None, None, Some(FilterReason::Mapq), Some(FilterReason::AlignedLength), Some(FilterReason::SoftClip), Some(FilterReason::Identity), Some(FilterReason::Identity)] {
+            counts.count(reason);
+        }
+        let mut total = FilterCounts::default();
+        total.add(&counts);
+        total.add(&counts);
+        assert_eq!(counts.to_string(), "2 reads scored, removed by filters: mapq 1, aligned length 1, soft clip 1, identity 2");
+        assert_eq!(total.to_string(), "4 reads scored, removed by filters: mapq 2, aligned length 2, soft clip 2, identity 4");
+    }
+}
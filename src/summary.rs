@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+
+use crate::homopolymer::{HomopolymerIndex, HomopolymerRecord, HomopolymerScore};
+
+/// read group used for reads without an RG tag
+pub const NO_READ_GROUP: &str = "NA";
+
+/// The scores of every read at one homopolymer
+#[derive(Debug, Clone, Default)]
+pub struct SiteSummary {
+    /// number of reads with each length difference
+    pub differences: BTreeMap<i32, u64>,
+    /// reads scored "?"
+    pub unknown: u64,
+    pub skip: u64,
+    pub mismatch: u64,
+}
+
+impl SiteSummary {
+    pub fn add(&mut self, score: &HomopolymerScore) {
+        match score {
+            HomopolymerScore::Difference(difference) => *self.differences.entry(*difference).or_insert(0) += 1,
+            HomopolymerScore::Other(other) => match other.as_str() {
+                "skip" => self.skip += 1,
+                "mismatch" => self.mismatch += 1,
+                _ => self.unknown += 1,
+            },
+        }
+    }
+
+    /// add the reads counted in `other`
+    pub fn merge(&mut self, other: &SiteSummary) {
+        for (&difference, &n) in &other.differences {
+            *self.differences.entry(difference).or_insert(0) += n;
+        }
+        self.unknown += other.unknown;
+        self.skip += other.skip;
+        self.mismatch += other.mismatch;
+    }
+
+    /// every read that covered the site, whatever its score
+    pub fn depth(&self) -> u64 {
+        self.differences.values().sum::<u64>() + self.unknown + self.skip + self.mismatch
+    }
+
+    /// reads with a difference of 0 as a fraction of the reads that were not skipped
+    pub fn fraction_correct(&self) -> Option<f64> {
+        let scored = self.depth() - self.skip;
+        if scored == 0 {
+            return None
+        }
+        Some(*self.differences.get(&0).unwrap_or(&0) as f64 / scored as f64)
+    }
+
+    pub fn mean_difference(&self) -> Option<f64> {
+        let reads: u64 = self.differences.values().sum();
+        if reads == 0 {
+            return None
+        }
+        let total: i64 = self.differences.iter().map(|(&difference, &n)| difference as i64 * n as i64).sum();
+        Some(total as f64 / reads as f64)
+    }
+
+    /// the most common difference, taking the one closest to 0 if there is a tie
+    pub fn modal_difference(&self) -> Option<i32> {
+        self.differences.iter()
+            .max_by_key(|(&difference, &n)| (n, std::cmp::Reverse((difference.abs(), difference))))
+            .map(|(&difference, _)| difference)
+    }
+}
+
+/// Summaries of one sample's reads at every homopolymer, split by read group
+#[derive(Debug, Default)]
+pub struct SiteSummaries {
+    sites: HashMap<(String, u32, u32), BTreeMap<String, SiteSummary>>,
+    read_groups: BTreeSet<String>,
+}
+
+impl SiteSummaries {
+    pub fn add(&mut self, homo: &HomopolymerRecord, read_group: Option<&str>, score: &HomopolymerScore) {
+        let read_group = read_group.unwrap_or(NO_READ_GROUP);
+        if !self.read_groups.contains(read_group) {
+            self.read_groups.insert(read_group.to_string());
+        }
+        self.sites.entry((homo.contig.clone(), homo.start, homo.stop)).or_default()
+            .entry(read_group.to_string()).or_default()
+            .add(score);
+    }
+
+    pub fn get(&self, homo: &HomopolymerRecord, read_group: &str) -> Option<&SiteSummary> {
+        self.sites.get(&(homo.contig.clone(), homo.start, homo.stop)).and_then(|groups| groups.get(read_group))
+    }
+
+    /// all the reads at `homo`, whatever their read group
+    pub fn combined(&self, homo: &HomopolymerRecord) -> SiteSummary {
+        let mut combined = SiteSummary::default();
+        if let Some(groups) = self.sites.get(&(homo.contig.clone(), homo.start, homo.stop)) {
+            for site in groups.values() {
+                combined.merge(site);
+            }
+        }
+        combined
+    }
+
+    /// read groups seen in the sample, or just `NO_READ_GROUP` if there were no reads
+    pub fn read_groups(&self) -> Vec<&str> {
+        if self.read_groups.is_empty() {
+            return vec![NO_READ_GROUP]
+        }
+        self.read_groups.iter().map(|rg| rg.as_str()).collect()
+    }
+
+    /// Write a row for every homopolymer in `homos` and every read group, in
+    /// homopolymer order. Sites no read covered have a depth of 0. `sample`
+    /// adds a sample column
+    pub fn write_rows<W: Write>(&self, homos: &HomopolymerIndex, sample: Option<&str>, writer: &mut W) -> std::io::Result<()> {
+        let empty = SiteSummary::default();
+        let read_groups = self.read_groups();
+        for contig in homos.contigs() {
+            for homo in homos.homopolymers(contig) {
+                for read_group in &read_groups {
+                    let site = self.get(homo, read_group).unwrap_or(&empty);
+                    let mut row = format!("{}\t{}\t{}\t{}\t{}", homo.contig, homo.start + 1, homo.stop, homo.base, homo.length);
+                    if let Some(sample) = sample {
+                        row.push('\t');
+                        row.push_str(sample);
+                    }
+                    let differences: Vec<String> = site.differences.iter().map(|(difference, n)| format!("{}:{}", difference, n)).collect();
+                    row.push_str(&format!("\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                        read_group,
+                        site.depth(),
+                        site.skip,
+                        site.unknown,
+                        site.mismatch,
+                        format_option(site.fraction_correct().map(|f| format!("{:.4}", f))),
+                        format_option(site.mean_difference().map(|m| format!("{:.4}", m))),
+                        format_option(site.modal_difference().map(|m| m.to_string())),
+                        if differences.is_empty() { "NA".to_string() } else { differences.join(",") },
+                    ));
+                    writer.write_all(row.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// header for the rows written by `SiteSummaries::write_rows`
+pub fn sites_header(sample: bool) -> String {
+    let mut header = String::from("contig\tstart\tstop\tbase\tlength");
+    if sample {
+        header.push_str("\tsample");
+    }
+    header.push_str("\tread_group\tdepth\tskip\tunknown\tmismatch\tfraction_correct\tmean_difference\tmodal_difference\tdifferences\n");
+    header
+}
+
+pub(crate) fn format_option(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "NA".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a summary of scores as written in the difference column
+    fn summary(scores: &[&str]) -> SiteSummary {
+        let mut summary = SiteSummary::default();
+        for score in scores {
+            summary.add(&score.parse().unwrap());
+        }
+        summary
+    }
+
+    #[test]
+    fn skip_and_unknown_scores() {
+        let site = summary(&["0", "0", "-1", "skip", "?", "mismatch", "0"]);
+        assert_eq!((site.depth(), site.skip, site.unknown, site.mismatch), (7, 1, 1, 1));
+        // "?" and mismatch count against the fraction correct, skip does not
+        assert_eq!(site.fraction_correct(), Some(3.0 / 6.0));
+        // only numerical differences are averaged
+        assert_eq!(site.mean_difference(), Some(-0.25));
+        assert_eq!(site.modal_difference(), Some(0));
+
+        let skipped = summary(&["skip", "skip"]);
+        assert_eq!(skipped.depth(), 2);
+        assert_eq!((skipped.fraction_correct(), skipped.mean_difference(), skipped.modal_difference()), (None, None, None));
+        let unknown = summary(&["?"]);
+        assert_eq!((unknown.fraction_correct(), unknown.mean_difference(), unknown.modal_difference()), (Some(0.0), None, None));
+        assert_eq!(SiteSummary::default().fraction_correct(), None);
+    }
+
+    #[test]
+    fn modal_difference_ties() {
+        assert_eq!(summary(&["3", "3", "3", "0", "0"]).modal_difference(), Some(3));
+        // a tie goes to the difference closest to 0, then to the deletion
+        assert_eq!(summary(&["1", "1", "-2", "-2"]).modal_difference(), Some(1));
+        assert_eq!(summary(&["-3", "-3", "2", "2"]).modal_difference(), Some(2));
+        assert_eq!(summary(&["1", "-1"]).modal_difference(), Some(-1));
+        assert_eq!(summary(&["-2", "2", "2", "-2"]).modal_difference(), Some(-2));
+    }
+
+    #[test]
+    fn merge() {
+        let mut site = summary(&["0", "skip"]);
+        site.merge(&summary(&["0", "1", "?", "mismatch"]));
+        assert_eq!(site.differences, BTreeMap::from([(0, 2), (1, 1)]));
+        assert_eq!((site.depth(), site.skip, site.unknown, site.mismatch), (6, 1, 1, 1));
+    }
+
+    #[test]
+    fn rows_by_read_group() {
+        let homo = |start: u32| HomopolymerRecord { contig: "c1".to_string(), start, stop: start + 5, base: "A".to_string(), length: 5 };
+        let homos = HomopolymerIndex::new(vec![homo(10), homo(30)]);
+        let mut sites = SiteSummaries::default();
+        for (read_group, score) in [(Some("rg1"), "0"), (Some("rg1"), "-1"), (None, "skip"), (Some("rg1"), "0")] {
+            sites.add(&homo(10), read_group, &score.parse().unwrap());
+        }
+        assert_eq!(sites.read_groups(), vec!["NA", "rg1"]);
+        assert_eq!(sites.combined(&homo(10)).depth(), 4);
+
+        let mut rows: Vec<u8> = Vec::new();
+        sites.write_rows(&homos, Some("s1"), &mut rows).unwrap();
+        assert_eq!(String::from_utf8(rows).unwrap(), "\
+c1\t11\t15\tA\t5\ts1\tNA\t1\t1\t0\t0\tNA\tNA\tNA\tNA
+c1\t11\t15\tA\t5\ts1\trg1\t3\t0\t0\t0\t0.6667\t-0.3333\t0\t-1:1,0:2
+c1\t31\t35\tA\t5\ts1\tNA\t0\t0\t0\t0\tNA\tNA\tNA\tNA
+c1\t31\t35\tA\t5\ts1\trg1\t0\t0\t0\t0\tNA\tNA\tNA\tNA
+");
+        assert_eq!(sites_header(true).split('\t').count(), 15);
+    }
+}